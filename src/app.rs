@@ -7,6 +7,7 @@ use std::sync::{Arc, RwLock};
 use tera::Tera;
 
 use crate::backend::Backend;
+use crate::compression::CompressionConfig;
 use crate::handlers::{
     api_default_handler,
     api_handler,
@@ -23,6 +24,8 @@ pub struct AppState {
     pub schema: Schema,
     pub backend: Box<Backend>,
     pub sql_templates: Option<Arc<RwLock<Tera>>>,
+    pub array_delimiter: String,
+    pub compression: CompressionConfig,
     pub debug: bool,
 }
 
@@ -31,10 +34,12 @@ pub fn create_app(
     backend: Box<Backend>,
     sql_templates: Option<Arc<RwLock<Tera>>>,
     api_key: Option<String>,
+    array_delimiter: String,
+    compression: CompressionConfig,
     debug: bool
     ) -> App<AppState>
 {
-    let app = App::with_state(AppState { schema, backend, sql_templates, debug })
+    let app = App::with_state(AppState { schema, backend, sql_templates, array_delimiter, compression, debug })
         .middleware(actix_middleware::Logger::default());
 
     let app = if let Some(ref key) = api_key {
@@ -70,3 +75,371 @@ pub fn create_app(
     app
 }
 
+
+/// Runs the whole app against a sqlite fixture, so that the http surface is
+/// tested without a database server.
+#[cfg(test)]
+mod test {
+    use actix_web::{HttpMessage, client::ClientResponse, test::TestServer};
+    use lazy_static::lazy_static;
+    use rusqlite::Connection;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::compression::{self, CompressionConfig};
+    use crate::db_config::get_db;
+
+    lazy_static! {
+        /// Built once per test run, from the same sql as `just create-test-sqlite`
+        static ref FIXTURE_DB: String = {
+            let path = std::env::temp_dir()
+                .join(format!("beryl-test-{}.db", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+
+            let conn = Connection::open(&path).expect("could not create fixture db");
+            conn.execute_batch(include_str!("../test/test-beryl-sqlite.sql"))
+                .expect("could not load fixture db");
+
+            path.to_str().expect("temp path is not unicode").to_owned()
+        };
+
+        /// A nullable sort key, and a float one with ties, for cursors. The
+        /// db and the schema.
+        static ref NULLS_FIXTURE: (String, String) = {
+            let path = std::env::temp_dir()
+                .join(format!("beryl-test-nulls-{}.db", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+
+            let conn = Connection::open(&path).expect("could not create fixture db");
+            conn.execute_batch("
+                create table scores (id integer not null, score integer, ratio real not null);
+                insert into scores values
+                    (1, 20, 0.1), (2, null, 0.3), (3, 10, 0.1), (4, 20, 0.1 + 0.2),
+                    (5, null, 0.1), (6, 10, 0.3), (7, 30, 1e-7);
+            ").expect("could not load fixture db");
+
+            let schema_path = path.with_extension("json");
+            std::fs::write(&schema_path, r#"{"endpoints": [{
+                "name": "scores",
+                "primary": "id",
+                "sql_select": {"table": {"name": "scores"}},
+                "interface": {
+                    "id": {"column": "id"},
+                    "score": {"column": "score", "type": "int"},
+                    "ratio": {"column": "ratio", "type": "float"}
+                }
+            }]}"#).expect("could not write fixture schema");
+
+            (
+                path.to_str().expect("temp path is not unicode").to_owned(),
+                schema_path.to_str().expect("temp path is not unicode").to_owned(),
+            )
+        };
+    }
+
+    fn test_server() -> TestServer {
+        fixture_server(concat!(env!("CARGO_MANIFEST_DIR"), "/test/schema.json"), &FIXTURE_DB)
+    }
+
+    fn fixture_server(schema_path: &str, db_path: &str) -> TestServer {
+        let schema = Schema::from_path(schema_path)
+            .expect("could not read test schema");
+        let (db, _, _) = get_db(&format!("sqlite://{}?pool_max=2", db_path))
+            .expect("could not open fixture db");
+        let compression = CompressionConfig {
+            min_size: compression::DEFAULT_MIN_SIZE,
+            level: None,
+        };
+
+        TestServer::with_factory(move || {
+            create_app(schema.clone(), db.clone(), None, None, ",".to_owned(), compression.clone(), false)
+        })
+    }
+
+    fn get(srv: &mut TestServer, path: &str) -> (ClientResponse, String) {
+        let request = srv.client(Method::GET, path).finish().unwrap();
+        let response = srv.execute(request.send()).unwrap();
+        let body = srv.execute(response.body()).unwrap();
+
+        (response, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn get_json(srv: &mut TestServer, path: &str) -> (ClientResponse, Value) {
+        let (response, body) = get(srv, path);
+
+        (response, serde_json::from_str(&body).unwrap())
+    }
+
+    fn header<'a>(response: &'a ClientResponse, name: &str) -> Option<&'a str> {
+        response.headers().get(name).map(|v| v.to_str().unwrap())
+    }
+
+    fn ids(records: &Value) -> Vec<i64> {
+        records["data"].as_array().unwrap().iter()
+            .map(|record| record["id"].as_i64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn api_csv() {
+        let mut srv = test_server();
+
+        let (response, body) = get(&mut srv, "/api/stores.csv?number_employees=gt.500&sort=id.asc&fields=id,city,name");
+
+        assert!(response.status().is_success());
+        assert_eq!(header(&response, "content-type"), Some("text/csv; charset=utf-8"));
+        assert_eq!(body, "id,city,name\n4,Malden MA,Store4\n5,Quincy MA,Store5\n");
+    }
+
+    #[test]
+    fn api_default_format_is_csv() {
+        let mut srv = test_server();
+
+        let (response, body) = get(&mut srv, "/api/stores?id=eq.3&fields=id,name");
+
+        assert!(response.status().is_success());
+        assert_eq!(body, "id,name\n3,Store3\n");
+    }
+
+    #[test]
+    fn api_negotiated_format_varies_by_accept() {
+        let mut srv = test_server();
+
+        let request = srv.client(Method::GET, "/api/stores?id=eq.3&fields=id,name")
+            .header("Accept", "application/x-ndjson")
+            .finish()
+            .unwrap();
+        let response = srv.execute(request.send()).unwrap();
+
+        assert!(response.status().is_success());
+        assert_eq!(header(&response, "content-type"), Some("application/x-ndjson"));
+        assert_eq!(header(&response, "vary"), Some("Accept, Accept-Encoding"));
+
+        let request = srv.client(Method::GET, "/api/stores/3")
+            .header("Accept", "image/png")
+            .finish()
+            .unwrap();
+        let response = srv.execute(request.send()).unwrap();
+
+        assert_eq!(response.status().as_u16(), 406);
+        assert_eq!(header(&response, "vary"), Some("Accept, Accept-Encoding"));
+
+        // with an extension, the format doesn't depend on the header
+        let (response, _) = get(&mut srv, "/api/stores.csv?id=eq.3");
+
+        assert_eq!(header(&response, "vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn api_filters() {
+        let mut srv = test_server();
+
+        let cases = vec![
+            ("name=starts.store", vec![1, 2, 3, 4, 5]),
+            ("name=store_", vec![]),
+            ("name=contains.store", vec![]),
+            ("name=match.%5EStore%5B24%5D%24", vec![2, 4]),
+            ("cities=Boston%20MA,Quincy%20MA", vec![1, 5]),
+            ("stocks_product=NIKE,~FILO", vec![2]),
+            ("number_employees=between.200.700", vec![2, 3, 5]),
+            ("opened=range.%5B2016-07-15,2019-05-05)", vec![2, 3]),
+            ("or=(city.eq.Boston%20MA,number_employees.gt.500)", vec![1, 4, 5]),
+            ("opened=is.null", vec![]),
+        ];
+
+        for (query, expected) in cases {
+            let path = format!("/api/stores.jsonrecords?sort=id.asc&{}", query);
+            let (response, records) = get_json(&mut srv, &path);
+
+            assert!(response.status().is_success(), "{}", query);
+            assert_eq!(ids(&records), expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn api_bad_filter_is_400() {
+        let mut srv = test_server();
+
+        for query in &["number_employees=gt.--1", "number_employees=gt.1%20or%201=1", "opened=gte.yesterday"] {
+            let (response, _) = get(&mut srv, &format!("/api/stores?{}", query));
+
+            assert_eq!(response.status().as_u16(), 400, "{}", query);
+        }
+    }
+
+    #[test]
+    fn api_regex_is_opt_in_and_capped() {
+        let mut srv = test_server();
+
+        // one char over the cap
+        let long_regex = "a".repeat(101);
+        for query in &["delivers=match.a".to_owned(), format!("name=match.{}", long_regex)] {
+            let (response, _) = get(&mut srv, &format!("/api/stores?{}", query));
+
+            assert!(response.status().is_client_error(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn api_unknown_endpoint_is_404() {
+        let mut srv = test_server();
+
+        let (response, _) = get(&mut srv, "/api/nope");
+
+        assert_eq!(response.status().as_u16(), 404);
+    }
+
+    #[test]
+    fn api_count() {
+        let mut srv = test_server();
+
+        let (response, records) = get_json(&mut srv, "/api/stores.jsonrecords?sort=id.asc&limit=2&count=exact&fields=id");
+
+        assert_eq!(header(&response, "x-total-count"), Some("5"));
+        assert!(header(&response, "link").unwrap().contains("limit=4,2>; rel=\"last\""));
+        assert_eq!(records["count"], 5);
+        assert_eq!(ids(&records), vec![1, 2]);
+    }
+
+    #[test]
+    fn api_xlsx_limit_is_within_the_row_cap() {
+        let mut srv = test_server();
+
+        for query in &["limit=1048576", "limit=10,1048576"] {
+            let (response, _) = get(&mut srv, &format!("/api/stores.xlsx?{}", query));
+
+            assert_eq!(response.status().as_u16(), 400, "{}", query);
+        }
+
+        // without a limit, the row cap is the limit
+        for query in &["", "limit=1048575"] {
+            let request = srv.client(Method::GET, &format!("/api/stores.xlsx?{}", query)).finish().unwrap();
+            let response = srv.execute(request.send()).unwrap();
+            let body = srv.execute(response.body()).unwrap();
+
+            assert!(response.status().is_success(), "{}", query);
+            assert_eq!(
+                header(&response, "content-type"),
+                Some("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+            );
+            // an xlsx file is a zip archive
+            assert!(body.starts_with(b"PK"), "{}", query);
+        }
+    }
+
+    #[test]
+    fn api_cursor() {
+        let mut srv = test_server();
+
+        let pages = cursor_pages(&mut srv, "stores", "limit=2&fields=id");
+
+        assert_eq!(pages, vec![vec![1, 2], vec![3, 4], vec![5]]);
+    }
+
+    /// Follows the cursors from the first page, returning the ids of each page.
+    fn cursor_pages(srv: &mut TestServer, endpoint: &str, query: &str) -> Vec<Vec<i64>> {
+        let mut pages = vec![];
+        let mut cursor = "*".to_owned();
+        loop {
+            let path = format!("/api/{}.jsonrecords?{}&cursor={}", endpoint, query, cursor);
+            let (response, records) = get_json(srv, &path);
+
+            assert!(response.status().is_success(), "{}", path);
+            pages.push(ids(&records));
+
+            match header(&response, "x-next-cursor") {
+                Some(next) => cursor = next.to_owned(),
+                None => break,
+            }
+        }
+
+        pages
+    }
+
+    #[test]
+    fn api_cursor_with_nulls() {
+        let (ref db_path, ref schema_path) = *NULLS_FIXTURE;
+        let mut srv = fixture_server(schema_path, db_path);
+
+        let cases = vec![
+            ("sort=score.asc", vec![3, 6, 1, 4, 7, 2, 5]),
+            ("sort=score.desc", vec![7, 4, 1, 6, 3, 5, 2]),
+            ("sort=score.asc.nullsfirst", vec![2, 5, 3, 6, 1, 4, 7]),
+            ("sort=score.desc.nullsfirst", vec![5, 2, 7, 4, 1, 6, 3]),
+        ];
+
+        for (query, expected) in cases {
+            let pages = cursor_pages(&mut srv, "scores", &format!("limit=2&{}", query));
+
+            assert!(pages.iter().all(|page| page.len() <= 2), "{}", query);
+            assert_eq!(pages.concat(), expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn api_cursor_with_float_ties() {
+        let (ref db_path, ref schema_path) = *NULLS_FIXTURE;
+        let mut srv = fixture_server(schema_path, db_path);
+
+        // 0.1 and 0.3 are tied across page boundaries, and 0.1 + 0.2 is
+        // just above 0.3
+        let cases = vec![
+            ("sort=ratio.asc", vec![vec![7, 1], vec![3, 5], vec![2, 6], vec![4]]),
+            ("sort=ratio.desc", vec![vec![4, 6], vec![2, 5], vec![3, 1], vec![7]]),
+        ];
+
+        for (query, expected) in cases {
+            let pages = cursor_pages(&mut srv, "scores", &format!("limit=2&{}", query));
+
+            assert_eq!(pages, expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn api_single() {
+        let mut srv = test_server();
+
+        let (response, records) = get_json(&mut srv, "/api/stores/2.jsonrecords");
+
+        assert!(response.status().is_success());
+        assert_eq!(ids(&records), vec![2]);
+        assert_eq!(records["data"][0]["name"], "Store2");
+
+        let (response, records) = get_json(&mut srv, "/api/stores/6.jsonrecords");
+
+        assert!(response.status().is_success());
+        assert!(ids(&records).is_empty());
+    }
+
+    #[test]
+    fn metadata() {
+        let mut srv = test_server();
+
+        let (response, metadata) = get_json(&mut srv, "/metadata/stores");
+
+        assert!(response.status().is_success());
+        assert_eq!(metadata["name"], "stores");
+        assert_eq!(metadata["primary_field"], "id");
+        assert!(metadata["fields"].as_array().unwrap().iter().any(|field| field["name"] == "city"));
+
+        let filter = |name: &str| {
+            metadata["filters"].as_array().unwrap().iter()
+                .find(|filter| filter["name"] == name)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(
+            filter("number_employees")["null_semantics"],
+            "Values, including with eq, neq, lt, lte, gt, gte, between, range, never match nulls; use is.null or not.null instead",
+        );
+        assert_eq!(
+            filter("cities")["null_semantics"],
+            "Values, including with ~, never match nulls; use is.null or not.null instead",
+        );
+
+        let (response, all) = get_json(&mut srv, "/metadata");
+
+        assert!(response.status().is_success());
+        assert_eq!(all[0], metadata);
+    }
+}
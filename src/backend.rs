@@ -1,19 +1,38 @@
 use failure::Error;
-use futures::Future;
+use futures::{Future, Stream, future};
 
 use crate::dataframe::DataFrame;
-use crate::query_ir::QueryIr;
+use crate::query::CountQuery;
+use crate::query_ir::{QueryIr, FilterValue};
+use crate::schema::Schema;
 
 pub trait Backend {
-    /// Takes in a SQL string, outputs a DataFrame, which will go on to be formatted into the
-    /// desired query output format.
-    fn exec_sql(&self, sql: String) -> Box<Future<Item=DataFrame, Error=Error>>;
+    /// Takes in a SQL query, outputs a stream of DataFrames, which will go on to be formatted
+    /// into the desired query output format.
+    ///
+    /// Each DataFrame is a chunk of rows (with the same columns), so that large
+    /// results don't have to be held in memory all at once.
+    fn exec_sql(&self, sql: SqlQuery) -> Box<Stream<Item=DataFrame, Error=Error>>;
 
     fn box_clone(&self) -> Box<dyn Backend + Send + Sync>;
 
     /// takes &self, but only required to be able to trun Backend into
     /// a trait object. It's not needed for any of the logic
-    fn generate_sql(&self, query_ir: QueryIr) -> String;
+    fn generate_sql(&self, query_ir: QueryIr) -> SqlQuery;
+
+    /// Generates sql which counts the rows matching the filters of the
+    /// query_ir, ignoring its projection, sort and limit. The sql must return
+    /// one row, with the count in its first column.
+    ///
+    /// Backends without statistics to estimate from may always count exactly.
+    fn generate_count_sql(&self, query_ir: QueryIr, count: &CountQuery) -> SqlQuery;
+
+    /// Checks the schema against the database before the server starts, so
+    /// that columns the backend can't read fail at startup instead of on
+    /// every query. Most backends have nothing to check.
+    fn check_schema(&self, _schema: &Schema) -> Box<Future<Item=(), Error=Error>> {
+        Box::new(future::ok(()))
+    }
 }
 
 impl Clone for Box<dyn Backend + Send + Sync> {
@@ -21,3 +40,17 @@ impl Clone for Box<dyn Backend + Send + Sync> {
         self.box_clone()
     }
 }
+
+/// Number of rows per DataFrame, for backends which chunk rows themselves.
+pub const CHUNK_SIZE: usize = 10_000;
+
+/// A SQL statement and the values to bind to its placeholders.
+///
+/// Filter values from the query must never be spliced into `sql`
+/// directly; backends whose driver can bind parameters put them in
+/// `params`, and those that can't must escape them for their dialect.
+#[derive(Debug, Clone)]
+pub struct SqlQuery {
+    pub sql: String,
+    pub params: Vec<FilterValue>,
+}
@@ -1,19 +1,20 @@
 use clickhouse_rs::Pool;
 use clickhouse_rs::types::Options;
-use failure::Error;
-use futures::Future;
-use log::*;
-use std::time::{Duration, Instant};
+use failure::{Error, bail};
+use futures::{Future, Stream, future};
+use std::time::Duration;
 
-use crate::backend::Backend;
+use crate::backend::{Backend, SqlQuery};
 use crate::dataframe::DataFrame;
-use crate::query_ir::QueryIr;
+use crate::query::CountQuery;
+use crate::query_ir::{QueryIr, FilterValue};
+use crate::schema::{Endpoint, Schema, SqlSelect};
 
 mod df;
 mod sql;
 
 use self::df::block_to_df;
-use self::sql::clickhouse_sql;
+use self::sql::{clickhouse_sql, clickhouse_count_sql, literal};
 
 // Ping timeout in millis
 const PING_TIMEOUT: u64 = 100_000;
@@ -41,30 +42,169 @@ impl Clickhouse {
 }
 
 impl Backend for Clickhouse {
-    fn exec_sql(&self, sql: String) -> Box<Future<Item=DataFrame, Error=Error>> {
-        let time_start = Instant::now();
-
-        let fut = self.pool
+    fn exec_sql(&self, sql: SqlQuery) -> Box<Stream<Item=DataFrame, Error=Error>> {
+        let stream = self.pool
             .get_handle()
-            .and_then(move |c| c.query(&sql[..]).fetch_all())
+            .map(move |c| c.query(&sql.sql[..]).stream_blocks())
+            .flatten_stream()
             .from_err()
-            .and_then(move |(_, block)| {
-                let timing = time_start.elapsed();
-                info!("Time for sql execution: {}.{:03}", timing.as_secs(), timing.subsec_millis());
+            .and_then(|block| {
                 //debug!("Block: {:?}", block);
-
                 Ok(block_to_df(block)?)
             });
 
-        Box::new(fut)
+        Box::new(stream)
     }
 
-    fn generate_sql(&self, query_ir: QueryIr) -> String {
+    fn generate_sql(&self, query_ir: QueryIr) -> SqlQuery {
         clickhouse_sql(query_ir)
     }
 
+    fn generate_count_sql(&self, query_ir: QueryIr, _count: &CountQuery) -> SqlQuery {
+        clickhouse_count_sql(query_ir)
+    }
+
+    /// clickhouse_rs fails to read a whole block if any column has a type
+    /// it doesn't know, so those columns are rejected at startup.
+    fn check_schema(&self, schema: &Schema) -> Box<Future<Item=(), Error=Error>> {
+        let checks: Vec<_> = schema.endpoints.iter()
+            .filter_map(|endpoint| {
+                let table = match endpoint.sql_select {
+                    SqlSelect::Table { ref name } => name,
+                    // a template's columns can't be known before it's rendered
+                    SqlSelect::Template { .. } => return None,
+                };
+
+                let sql = columns_sql(table);
+                let endpoint = endpoint.clone();
+                let check = self.pool
+                    .get_handle()
+                    .and_then(move |c| c.query(&sql[..]).fetch_all())
+                    .from_err()
+                    .and_then(move |(_, block)| {
+                        let mut column_types = vec![];
+                        for row_idx in 0..block.row_count() {
+                            column_types.push((
+                                block.get::<String, _>(row_idx, "name")?,
+                                block.get::<String, _>(row_idx, "type")?,
+                            ));
+                        }
+
+                        check_column_types(&endpoint, &column_types)
+                    });
+
+                Some(check)
+            })
+            .collect();
+
+        Box::new(future::join_all(checks).map(|_| ()))
+    }
+
     // https://users.rust-lang.org/t/solved-is-it-possible-to-clone-a-boxed-trait-object/1714/4
     fn box_clone(&self) -> Box<dyn Backend + Send + Sync> {
         Box::new((*self).clone())
     }
 }
+
+/// The names and types of a table's columns. The table can be qualified by
+/// its database.
+fn columns_sql(table: &str) -> String {
+    let (database_sql, table) = match table.find('.') {
+        Some(idx) => (literal(&FilterValue::Text(table[..idx].to_owned())), &table[idx + 1..]),
+        None => ("currentDatabase()".to_owned(), table),
+    };
+
+    format!("select name, type from system.columns where database = {} and table = {}",
+        database_sql,
+        literal(&FilterValue::Text(table.to_owned())),
+    )
+}
+
+/// Enum8, Enum16 and UUID (also when Nullable, LowCardinality or in an
+/// Array) can't be read by clickhouse_rs.
+fn check_column_types(endpoint: &Endpoint, column_types: &[(String, String)]) -> Result<(), Error> {
+    for param in endpoint.interface.0.values() {
+        let sql_type = column_types.iter()
+            .find(|(name, _)| *name == param.column)
+            .map(|(_, sql_type)| sql_type);
+
+        if let Some(sql_type) = sql_type {
+            let mut inner_type = &sql_type[..];
+            while let Some(idx) = ["Nullable(", "LowCardinality(", "Array("].iter()
+                .find(|wrapper| inner_type.starts_with(*wrapper))
+                .map(|wrapper| wrapper.len())
+            {
+                inner_type = &inner_type[idx..];
+            }
+
+            if ["Enum8", "Enum16", "UUID"].iter().any(|t| inner_type.starts_with(t)) {
+                bail!("Column '{}' of endpoint '{}' has type {}, which the clickhouse driver can't read; \
+                    convert it in the schema instead, e.g. \"column\": \"toString({})\"",
+                    param.column,
+                    endpoint.name,
+                    sql_type,
+                    param.column,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stores() -> Endpoint {
+        let schema = Schema::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/test/schema.json")).unwrap();
+
+        schema.get_endpoint("stores").unwrap()
+    }
+
+    fn column_types(city_state_type: &str) -> Vec<(String, String)> {
+        vec![
+            ("account".to_owned(), "UInt32".to_owned()),
+            ("city_state".to_owned(), city_state_type.to_owned()),
+            ("store_label".to_owned(), "String".to_owned()),
+            // not in the interface, so never selected
+            ("other".to_owned(), "UUID".to_owned()),
+        ]
+    }
+
+    #[test]
+    fn unreadable_columns_are_rejected() {
+        let endpoint = stores();
+
+        for readable in &["String", "LowCardinality(String)", "Nullable(FixedString(2))", "Array(String)"] {
+            assert!(check_column_types(&endpoint, &column_types(readable)).is_ok(), "{}", readable);
+        }
+
+        let unreadable = [
+            "Enum8('a' = 1, 'b' = 2)",
+            "Enum16('a' = 1)",
+            "UUID",
+            "Nullable(UUID)",
+            "LowCardinality(Nullable(Enum8('a' = 1)))",
+            "Array(Enum8('a' = 1))",
+        ];
+        for sql_type in &unreadable {
+            let err = check_column_types(&endpoint, &column_types(sql_type)).unwrap_err();
+
+            assert!(err.to_string().contains("Column 'city_state' of endpoint 'stores'"), "{}", err);
+            assert!(err.to_string().contains("toString(city_state)"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn columns_sql_escapes_table() {
+        assert_eq!(
+            columns_sql("t'x"),
+            "select name, type from system.columns where database = currentDatabase() and table = 't\\'x'",
+        );
+        assert_eq!(
+            columns_sql("db.t"),
+            "select name, type from system.columns where database = 'db' and table = 't'",
+        );
+    }
+}
@@ -1,8 +1,12 @@
 //! Convert clickhouse Block to tesseract_core::DataFrame
 
-use failure::{Error, bail};
+// clickhouse-rs returns dates as the deprecated `chrono::Date`
+#[allow(deprecated)]
+use chrono::{Date, DateTime};
+use chrono_tz::Tz;
+use failure::{Error, bail, format_err};
 
-use clickhouse_rs::types::{Block, SqlType};
+use clickhouse_rs::types::{Block, Decimal, SqlType};
 use crate::dataframe::{DataFrame, Column, ColumnData};
 
 // from source code of clickhouse_rs
@@ -19,7 +23,14 @@ use crate::dataframe::{DataFrame, Column, ColumnData};
 //            SqlType::Float64 => "Float64".into(),
 //            SqlType::Date => "Date".into(),
 //            SqlType::DateTime => "DateTime".into(),
+//
+// LowCardinality(String) is sent as String, since clickhouse_rs uses a
+// protocol revision from before LowCardinality. Enum8, Enum16 and UUID
+// aren't supported by clickhouse_rs, which fails when reading the block;
+// those columns must be converted with `toString` in the schema's `column`,
+// which `Clickhouse::check_schema` enforces at startup.
 
+#[allow(deprecated)]
 pub fn block_to_df(block: Block) -> Result<DataFrame, Error> {
     let mut df = vec![];
 
@@ -91,6 +102,32 @@ pub fn block_to_df(block: Block) -> Result<DataFrame, Error> {
                     ColumnData::Float64(vec![]),
                 ))
             },
+            SqlType::Date => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::Date(vec![]),
+                ))
+            },
+            SqlType::DateTime => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::DateTime(vec![]),
+                ))
+            },
+            SqlType::Decimal(_, _) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::Decimal(vec![]),
+                ))
+            },
+            // FixedString is often used for binary data, so it's checked as
+            // utf8 when read
+            SqlType::FixedString(_) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::Text(vec![]),
+                ))
+            },
             SqlType::Nullable(SqlType::UInt8) => {
                 df.push(Column::new(
                     col.name().to_owned(),
@@ -157,10 +194,101 @@ pub fn block_to_df(block: Block) -> Result<DataFrame, Error> {
                     ColumnData::NullableFloat64(vec![]),
                 ))
             },
+            SqlType::Nullable(SqlType::Date) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::NullableDate(vec![]),
+                ))
+            },
+            SqlType::Nullable(SqlType::DateTime) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::NullableDateTime(vec![]),
+                ))
+            },
+            SqlType::Nullable(SqlType::Decimal(_, _)) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::NullableDecimal(vec![]),
+                ))
+            },
+            SqlType::Nullable(SqlType::FixedString(_)) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::NullableText(vec![]),
+                ))
+            },
+            // clickhouse-rs can't read Array(Float32) or Array(Float64),
+            // so those fall through to not supported.
+            SqlType::Array(SqlType::UInt8) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::ArrayUInt8(vec![]),
+                ))
+            },
+            SqlType::Array(SqlType::UInt16) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::ArrayUInt16(vec![]),
+                ))
+            },
+            SqlType::Array(SqlType::UInt32) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::ArrayUInt32(vec![]),
+                ))
+            },
+            SqlType::Array(SqlType::UInt64) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::ArrayUInt64(vec![]),
+                ))
+            },
+            SqlType::Array(SqlType::Int8) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::ArrayInt8(vec![]),
+                ))
+            },
+            SqlType::Array(SqlType::Int16) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::ArrayInt16(vec![]),
+                ))
+            },
+            SqlType::Array(SqlType::Int32) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::ArrayInt32(vec![]),
+                ))
+            },
+            SqlType::Array(SqlType::Int64) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::ArrayInt64(vec![]),
+                ))
+            },
+            SqlType::Array(SqlType::String) => {
+                df.push(Column::new(
+                    col.name().to_owned(),
+                    ColumnData::ArrayText(vec![]),
+                ))
+            },
             s => bail!("{} is not supported by tesseract", s),
         }
     }
 
+    // only FixedString is padded; a String's trailing null bytes are data
+    let is_padded: Vec<bool> = block.columns().iter()
+        .map(|col| {
+            match col.sql_type() {
+                SqlType::FixedString(_) |
+                SqlType::Nullable(SqlType::FixedString(_)) => true,
+                _ => false,
+            }
+        })
+        .collect();
+
     for row_idx in 0..block.row_count() {
         for col_idx in 0..block.column_count() {
             let column_data = df
@@ -194,7 +322,8 @@ pub fn block_to_df(block: Block) -> Result<DataFrame, Error> {
                     col_data.push(block.get(row_idx, col_idx)?);
                 },
                 ColumnData::Text(col_data) => {
-                    col_data.push(block.get(row_idx, col_idx)?);
+                    let val: &[u8] = block.get(row_idx, col_idx)?;
+                    col_data.push(bytes_to_string(val, is_padded[col_idx], col_idx)?);
                 },
                 ColumnData::Float32(col_data) => {
                     col_data.push(block.get(row_idx, col_idx)?);
@@ -227,7 +356,8 @@ pub fn block_to_df(block: Block) -> Result<DataFrame, Error> {
                     col_data.push(block.get(row_idx, col_idx)?);
                 },
                 ColumnData::NullableText(col_data) => {
-                    col_data.push(block.get(row_idx, col_idx)?);
+                    let val: Option<&[u8]> = block.get(row_idx, col_idx)?;
+                    col_data.push(val.map(|v| bytes_to_string(v, is_padded[col_idx], col_idx)).transpose()?);
                 },
                 ColumnData::NullableFloat32(col_data) => {
                     col_data.push(block.get(row_idx, col_idx)?);
@@ -235,9 +365,141 @@ pub fn block_to_df(block: Block) -> Result<DataFrame, Error> {
                 ColumnData::NullableFloat64(col_data) => {
                     col_data.push(block.get(row_idx, col_idx)?);
                 },
+                // clickhouse-rs returns dates in the client's timezone
+                // (utc unless set in the url), so they're made naive here.
+                ColumnData::Date(col_data) => {
+                    let val: Date<Tz> = block.get(row_idx, col_idx)?;
+                    col_data.push(val.naive_local());
+                },
+                ColumnData::DateTime(col_data) => {
+                    let val: DateTime<Tz> = block.get(row_idx, col_idx)?;
+                    col_data.push(val.naive_local());
+                },
+                ColumnData::NullableDate(col_data) => {
+                    let val: Option<Date<Tz>> = block.get(row_idx, col_idx)?;
+                    col_data.push(val.map(|d| d.naive_local()));
+                },
+                ColumnData::NullableDateTime(col_data) => {
+                    let val: Option<DateTime<Tz>> = block.get(row_idx, col_idx)?;
+                    col_data.push(val.map(|d| d.naive_local()));
+                },
+                ColumnData::Decimal(col_data) => {
+                    let val: Decimal = block.get(row_idx, col_idx)?;
+                    col_data.push(decimal_to_string(&val));
+                },
+                ColumnData::NullableDecimal(col_data) => {
+                    let val: Option<Decimal> = block.get(row_idx, col_idx)?;
+                    col_data.push(val.map(|d| decimal_to_string(&d)));
+                },
+                ColumnData::ArrayUInt8(col_data) => {
+                    col_data.push(block.get(row_idx, col_idx)?);
+                },
+                ColumnData::ArrayUInt16(col_data) => {
+                    col_data.push(block.get(row_idx, col_idx)?);
+                },
+                ColumnData::ArrayUInt32(col_data) => {
+                    col_data.push(block.get(row_idx, col_idx)?);
+                },
+                ColumnData::ArrayUInt64(col_data) => {
+                    col_data.push(block.get(row_idx, col_idx)?);
+                },
+                ColumnData::ArrayInt8(col_data) => {
+                    col_data.push(block.get(row_idx, col_idx)?);
+                },
+                ColumnData::ArrayInt16(col_data) => {
+                    col_data.push(block.get(row_idx, col_idx)?);
+                },
+                ColumnData::ArrayInt32(col_data) => {
+                    col_data.push(block.get(row_idx, col_idx)?);
+                },
+                ColumnData::ArrayInt64(col_data) => {
+                    col_data.push(block.get(row_idx, col_idx)?);
+                },
+                ColumnData::ArrayText(col_data) => {
+                    col_data.push(block.get(row_idx, col_idx)?);
+                },
+                ColumnData::ArrayFloat32(_) | ColumnData::ArrayFloat64(_) => {
+                    unreachable!("float arrays are not created from clickhouse blocks");
+                },
+                ColumnData::NullableArrayInt8(_) |
+                ColumnData::NullableArrayInt16(_) |
+                ColumnData::NullableArrayInt32(_) |
+                ColumnData::NullableArrayInt64(_) |
+                ColumnData::NullableArrayUInt8(_) |
+                ColumnData::NullableArrayUInt16(_) |
+                ColumnData::NullableArrayUInt32(_) |
+                ColumnData::NullableArrayUInt64(_) |
+                ColumnData::NullableArrayFloat32(_) |
+                ColumnData::NullableArrayFloat64(_) |
+                ColumnData::NullableArrayText(_) => {
+                    unreachable!("nullable arrays are not created from clickhouse blocks");
+                },
             }
         }
     }
 
     Ok(DataFrame::from_vec(df))
 }
+
+/// Formats a decimal from its unscaled value, so that it's exact.
+/// (clickhouse-rs's `Display` for `Decimal` is wrong for negative values.)
+fn decimal_to_string(decimal: &Decimal) -> String {
+    unscaled_to_string(decimal.internal(), decimal.scale())
+}
+
+fn unscaled_to_string(unscaled: i64, scale: usize) -> String {
+    if scale == 0 {
+        return unscaled.to_string();
+    }
+
+    // `abs` would overflow on `i64::MIN`
+    let digits = format!("{:0width$}", unscaled.unsigned_abs(), width = scale + 1);
+    let (int_part, frac_part) = digits.split_at(digits.len() - scale);
+    let sign = if unscaled < 0 { "-" } else { "" };
+
+    format!("{}{}.{}", sign, int_part, frac_part)
+}
+
+/// String and FixedString values are bytes; FixedString is also padded
+/// with trailing null bytes, which are trimmed if `is_padded`.
+fn bytes_to_string(bytes: &[u8], is_padded: bool, col_idx: usize) -> Result<String, Error> {
+    let trimmed = if is_padded {
+        match bytes.iter().rposition(|b| *b != 0) {
+            Some(last) => &bytes[..=last],
+            None => &[],
+        }
+    } else {
+        bytes
+    };
+
+    let s = std::str::from_utf8(trimmed)
+        .map_err(|_| format_err!("Non utf8 string in column {} is not supported by beryl", col_idx))?;
+
+    Ok(s.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decimal_strings() {
+        assert_eq!(decimal_to_string(&Decimal::of(12345i64, 0)), "12345");
+        assert_eq!(unscaled_to_string(12345, 2), "123.45");
+        assert_eq!(unscaled_to_string(-12345, 2), "-123.45");
+        assert_eq!(unscaled_to_string(-5, 3), "-0.005");
+        assert_eq!(unscaled_to_string(0, 2), "0.00");
+        assert_eq!(unscaled_to_string(i64::MIN, 0), "-9223372036854775808");
+        assert_eq!(unscaled_to_string(i64::MIN, 4), "-922337203685477.5808");
+        assert_eq!(unscaled_to_string(i64::MIN, 18), "-9.223372036854775808");
+    }
+
+    #[test]
+    fn only_padded_strings_are_trimmed() {
+        assert_eq!(bytes_to_string(b"ab\0\0", true, 0).unwrap(), "ab");
+        assert_eq!(bytes_to_string(b"\0\0", true, 0).unwrap(), "");
+        assert_eq!(bytes_to_string(b"a\0b\0", true, 0).unwrap(), "a\0b");
+        assert_eq!(bytes_to_string(b"ab\0\0", false, 0).unwrap(), "ab\0\0");
+        assert!(bytes_to_string(b"\xff", false, 0).is_err());
+    }
+}
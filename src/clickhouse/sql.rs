@@ -1,118 +1,35 @@
 use itertools::join;
 
-use crate::query_ir::{QueryIr, Constraint};
+use crate::backend::SqlQuery;
+use crate::query_ir::{QueryIr, Constraint, CursorIr, FilterExpr, FilterIr, FilterValue, StringMatchKind};
 
-pub fn clickhouse_sql(query_ir: QueryIr) -> String {
+/// clickhouse_rs can't bind parameters, so filter values are
+/// rendered as escaped literals and `params` is always empty.
+pub fn clickhouse_sql(query_ir: QueryIr) -> SqlQuery {
     let project_cols_sql = join(query_ir.projection, ", ");
 
     let table = query_ir.table;
 
-    let filter_sql = if !query_ir.filters.is_empty() {
-        let filters = query_ir.filters.iter()
-            .map(|f| {
-                match &f.constraint {
-                    Constraint::CompareList ( ref compare_list )=> {
-                        let comparisons = compare_list.iter()
-                            .map(|compare| {
-                                let comparison = &compare.comparison;
-                                let n = &compare.n;
-
-                                let single_quote = if f.is_text {
-                                    "'".to_owned()
-                                } else {
-                                    "".to_owned()
-                                };
-                                format!("{} {} {single_quote}{}{single_quote}",
-                                    f.column,
-                                    comparison.sql_string(),
-                                    n,
-                                    single_quote = single_quote,
-                                )
-                            });
-
-                        join(comparisons, " and ")
-                    },
-                    Constraint::ExactMatch { ref pattern } => {
-                        let single_quote = if f.is_text {
-                            "'".to_owned()
-                        } else {
-                            "".to_owned()
-                        };
-                        format!("{} = {}{}{}",
-                            f.column,
-                            single_quote,
-                            pattern,
-                            single_quote,
-                        )
-                    },
-                    Constraint::StringMatch { ref substring } => {
-                        format!("lowerUTF8({}) LIKE '%{}%'",
-                            f.column,
-                            substring.to_lowercase(),
-                        )
-                    },
-                    Constraint::InArray { ref in_members, ref not_in_members } => {
-                        let single_quote = if f.is_text {
-                            "'".to_owned()
-                        } else {
-                            "".to_owned()
-                        };
-
-                        let mut res = String::new();
-                        if !in_members.is_empty() {
-                            let ms = in_members
-                                .iter()
-                                .map(|m| {
-                                    format!("{}{}{}",
-                                        single_quote,
-                                        m,
-                                        single_quote,
-                                    )
-                                });
-
-                            res.push_str(&format!("hasAll({}, [{}])",
-                                f.column,
-                                join(ms, ", "),
-                            ));
-                        };
-                        if !not_in_members.is_empty() {
-                            let ms = not_in_members
-                                .iter()
-                                .map(|m| {
-                                    format!("{}{}{}",
-                                        single_quote,
-                                        m,
-                                        single_quote,
-                                    )
-                                });
-
-                            if !in_members.is_empty() {
-                                res.push_str(" AND ");
-                            }
-
-                            res.push_str(&format!("NOT hasAny({}, [{}])",
-                                f.column,
-                                join(ms, ", "),
-                            ));
-                        };
-
-                        res
-                    },
+    let filter_sql = filter_sql(&query_ir.filters, query_ir.cursor.as_ref());
+
+    let sort_sql = if !query_ir.sort.is_empty() {
+        let sorts = query_ir.sort.iter()
+            .map(|srt| {
+                if let Some(ref nulls) = srt.nulls {
+                    format!("{} {} {}",
+                        srt.column,
+                        srt.direction.sql_string(),
+                        nulls.sql_string(),
+                    )
+                } else {
+                    format!("{} {}",
+                        srt.column,
+                        srt.direction.sql_string(),
+                    )
                 }
             });
 
-        let filters_str = join(filters, " and ");
-
-        format!("where {}", filters_str)
-    } else {
-        "".into()
-    };
-
-    let sort_sql = if let Some(srt) = query_ir.sort {
-        format!("order by {} {}",
-                srt.column,
-                srt.direction.sql_string(),
-        )
+        format!("order by {}", join(sorts, ", "))
     } else {
         "".into()
     };
@@ -129,11 +46,259 @@ pub fn clickhouse_sql(query_ir: QueryIr) -> String {
         }
     };
 
-    format!("select {} from {} {} {} {}",
+    let sql = format!("select {} from {} {} {} {}",
         project_cols_sql,
         table,
         filter_sql,
         sort_sql,
         limit_sql,
-    )
+    );
+
+    SqlQuery {
+        sql,
+        params: vec![],
+    }
+}
+
+/// Counts the rows matching the filters, ignoring sort and limit.
+///
+/// clickhouse counts a whole MergeTree table from its metadata, so the
+/// count is always exact.
+pub fn clickhouse_count_sql(query_ir: QueryIr) -> SqlQuery {
+    let filter_sql = filter_sql(&query_ir.filters, None);
+
+    let sql = format!("select count() from {} {}", query_ir.table, filter_sql);
+
+    SqlQuery {
+        sql,
+        params: vec![],
+    }
+}
+
+fn filter_sql(filter_exprs: &[FilterExpr], cursor: Option<&CursorIr>) -> String {
+    let mut filters: Vec<_> = filter_exprs.iter()
+        .map(|expr| expr.sql_string(&mut constraint_sql))
+        .collect();
+
+    // keyset pagination: the rows after the cursor, in the sort order
+    if let Some(cursor) = cursor {
+        let cursor_sql = cursor.sql_string(|col| col.to_owned(), literal);
+
+        filters.extend(cursor_sql);
+    }
+
+    if !filters.is_empty() {
+        format!("where {}", join(filters, " and "))
+    } else {
+        "".into()
+    }
+}
+
+fn constraint_sql(f: &FilterIr) -> String {
+    match &f.constraint {
+        Constraint::CompareList ( ref compare_list )=> {
+            let comparisons = compare_list.iter()
+                .map(|compare| {
+                    format!("{} {} {}",
+                        f.column,
+                        compare.comparison.sql_string(),
+                        literal(&compare.n),
+                    )
+                });
+
+            join(comparisons, " and ")
+        },
+        Constraint::ExactMatch { ref pattern } => {
+            format!("{} = {}",
+                f.column,
+                literal(pattern),
+            )
+        },
+        Constraint::StringMatch { ref kind, ref substring } => {
+            // LIKE escapes with `\`; `literal` escapes it again for the
+            // string literal
+            match kind {
+                StringMatchKind::Contains |
+                StringMatchKind::StartsWith |
+                StringMatchKind::EndsWith => {
+                    format!("lowerUTF8({}) LIKE {}",
+                        f.column,
+                        literal(&FilterValue::Text(kind.like_pattern(&substring.to_lowercase()))),
+                    )
+                },
+                StringMatchKind::ContainsCaseSensitive => {
+                    format!("{} LIKE {}",
+                        f.column,
+                        literal(&FilterValue::Text(kind.like_pattern(substring))),
+                    )
+                },
+            }
+        },
+        Constraint::RegexMatch { ref regex } => {
+            format!("match({}, {})",
+                f.column,
+                literal(&FilterValue::Text(regex.clone())),
+            )
+        },
+        Constraint::InArray { ref in_members, ref not_in_members } => {
+            let mut res = String::new();
+            if !in_members.is_empty() {
+                let ms = in_members
+                    .iter()
+                    .map(literal);
+
+                res.push_str(&format!("hasAll({}, [{}])",
+                    f.column,
+                    join(ms, ", "),
+                ));
+            };
+            if !not_in_members.is_empty() {
+                let ms = not_in_members
+                    .iter()
+                    .map(literal);
+
+                if !in_members.is_empty() {
+                    res.push_str(" AND ");
+                }
+
+                res.push_str(&format!("NOT hasAny({}, [{}])",
+                    f.column,
+                    join(ms, ", "),
+                ));
+            };
+
+            res
+        },
+        Constraint::OneOf { ref in_members, ref not_in_members } => {
+            let mut res = vec![];
+            if !in_members.is_empty() {
+                res.push(format!("{} IN ({})",
+                    f.column,
+                    join(in_members.iter().map(literal), ", "),
+                ));
+            }
+            if !not_in_members.is_empty() {
+                res.push(format!("{} NOT IN ({})",
+                    f.column,
+                    join(not_in_members.iter().map(literal), ", "),
+                ));
+            }
+
+            join(res, " AND ")
+        },
+        Constraint::IsNull => format!("{} IS NULL", f.column),
+        Constraint::IsNotNull => format!("{} IS NOT NULL", f.column),
+    }
+}
+
+/// Renders a filter value as a clickhouse literal.
+///
+/// Numbers, dates and decimals were already parsed, so only strings need
+/// escaping. In a clickhouse string literal, backslash is the escape
+/// character, so both backslashes and single quotes are escaped with it.
+pub fn literal(value: &FilterValue) -> String {
+    match value {
+        FilterValue::Text(s) => {
+            let escaped = s
+                .replace('\\', "\\\\")
+                .replace('\'', "\\'");
+            format!("'{}'", escaped)
+        },
+        FilterValue::Date(_) => format!("'{}'", value),
+        // clickhouse DateTime has no fractional seconds
+        FilterValue::DateTime(dt) => format!("'{}'", dt.format("%Y-%m-%d %H:%M:%S")),
+        FilterValue::Bool(b) => (*b as u8).to_string(),
+        FilterValue::Int(_) |
+        FilterValue::UInt(_) |
+        FilterValue::Float(_) |
+        FilterValue::Decimal(_) => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query_ir::test::{HOSTILE_VALUES, matches_literally, query_ir_with_every_constraint};
+
+    /// Replaces each string literal in the sql with `?`, and returns the
+    /// unescaped literals. Panics on an unterminated literal.
+    fn split_literals(sql: &str) -> (String, Vec<String>) {
+        let mut skeleton = String::new();
+        let mut literals = vec![];
+        let mut chars = sql.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '\'' {
+                skeleton.push(c);
+                continue;
+            }
+
+            let mut literal = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => literal.push(chars.next().expect("dangling escape")),
+                    Some('\'') => break,
+                    Some(c) => literal.push(c),
+                    None => panic!("unterminated literal in {}", sql),
+                }
+            }
+
+            skeleton.push('?');
+            literals.push(literal);
+        }
+
+        (skeleton, literals)
+    }
+
+    /// Values can only be rendered inside string literals, so the sql
+    /// outside them is the same whatever the values are.
+    fn assert_only_literals(value: &str, sql: &str, benign_sql: &str) {
+        let (skeleton, literals) = split_literals(sql);
+        let (benign_skeleton, benign_literals) = split_literals(benign_sql);
+
+        assert_eq!(skeleton, benign_skeleton, "sql changed for value {:?}", value);
+        assert_eq!(literals.len(), benign_literals.len());
+
+        for literal in literals {
+            assert!(matches_literally(&literal, value), "literal {:?} for value {:?}", literal, value);
+        }
+    }
+
+    #[test]
+    fn hostile_values_are_escaped() {
+        let benign = clickhouse_sql(query_ir_with_every_constraint("a"));
+
+        for value in HOSTILE_VALUES {
+            let sql_query = clickhouse_sql(query_ir_with_every_constraint(value));
+
+            assert!(sql_query.params.is_empty());
+            assert_only_literals(value, &sql_query.sql, &benign.sql);
+        }
+    }
+
+    #[test]
+    fn hostile_values_are_escaped_in_count() {
+        let benign = clickhouse_count_sql(query_ir_with_every_constraint("a"));
+
+        for value in HOSTILE_VALUES {
+            let sql_query = clickhouse_count_sql(query_ir_with_every_constraint(value));
+
+            assert_only_literals(value, &sql_query.sql, &benign.sql);
+        }
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        let SqlQuery { sql, .. } = clickhouse_sql(query_ir_with_every_constraint("100%_\\"));
+
+        // escaped once for LIKE, then again for the string literal
+        assert!(sql.contains("'%100\\\\%\\\\_\\\\\\\\%'"));
+    }
+
+    #[test]
+    fn non_text_values_are_not_quoted() {
+        assert_eq!(literal(&FilterValue::Int(-1)), "-1");
+        assert_eq!(literal(&FilterValue::Decimal("-1.5".to_owned())), "-1.5");
+        assert_eq!(literal(&FilterValue::Bool(true)), "1");
+    }
 }
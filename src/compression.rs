@@ -0,0 +1,138 @@
+//! Compression of response bodies, negotiated from `Accept-Encoding`.
+//!
+//! actix-web's own compression is turned off for data responses (it
+//! can't be configured, and doesn't support zstd), so that bodies are
+//! compressed here as they're streamed.
+
+use brotli2::write::BrotliEncoder;
+use failure::Error;
+use flate2::write::GzEncoder;
+use std::io::Write;
+
+/// Responses smaller than this aren't worth compressing.
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// In bytes; checked against the first chunk of the response.
+    pub min_size: usize,
+    /// Used for every encoding, capped at the encoding's maximum. `None`
+    /// uses each encoding's default.
+    pub level: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Zstd,
+    Br,
+    Gzip,
+}
+
+impl Encoding {
+    /// Picks the encoding with the highest quality in an `Accept-Encoding`
+    /// header. On a tie, the order of preference is zstd, br, gzip.
+    pub fn from_accept_encoding(accept_encoding: &str) -> Option<Self> {
+        let mut qualities = [(Encoding::Zstd, 0.0), (Encoding::Br, 0.0), (Encoding::Gzip, 0.0)];
+
+        for coding in accept_encoding.split(',') {
+            let mut parts = coding.split(';');
+
+            let name = parts.next()
+                .unwrap_or_default()
+                .trim()
+                .to_lowercase();
+
+            let quality = parts
+                .map(|param| param.trim())
+                .find(|param| param.starts_with("q="))
+                .map(|param| param[2..].parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+
+            for (encoding, q) in qualities.iter_mut() {
+                // `*` doesn't override an encoding that's named
+                if name == encoding.as_str() || (name == "*" && *q == 0.0) {
+                    *q = quality;
+                }
+            }
+        }
+
+        let mut best: Option<(Encoding, f32)> = None;
+
+        for (encoding, q) in qualities.iter() {
+            if *q > 0.0 && best.map(|(_, best_q)| *q > best_q).unwrap_or(true) {
+                best = Some((*encoding, *q));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+
+    /// The `Content-Encoding` value.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Br => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Compresses a body one chunk at a time.
+pub enum Compressor {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Br(BrotliEncoder<Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    pub fn new(encoding: Encoding, level: Option<u32>) -> Result<Self, Error> {
+        let compressor = match encoding {
+            Encoding::Zstd => {
+                let level = level.map(|l| l.min(22)).unwrap_or(3);
+                Compressor::Zstd(zstd::stream::write::Encoder::new(vec![], level as i32)?)
+            },
+            Encoding::Br => {
+                let level = level.map(|l| l.min(11)).unwrap_or(4);
+                Compressor::Br(BrotliEncoder::new(vec![], level))
+            },
+            Encoding::Gzip => {
+                let level = level.map(|l| l.min(9)).unwrap_or(6);
+                Compressor::Gzip(GzEncoder::new(vec![], flate2::Compression::new(level)))
+            },
+        };
+
+        Ok(compressor)
+    }
+
+    /// Returns the compressed output so far, which may be empty while
+    /// the encoder is buffering.
+    pub fn compress(&mut self, buf: &[u8]) -> Result<Vec<u8>, Error> {
+        let out = match self {
+            Compressor::Zstd(encoder) => {
+                encoder.write_all(buf)?;
+                std::mem::replace(encoder.get_mut(), vec![])
+            },
+            Compressor::Br(encoder) => {
+                encoder.write_all(buf)?;
+                std::mem::replace(encoder.get_mut(), vec![])
+            },
+            Compressor::Gzip(encoder) => {
+                encoder.write_all(buf)?;
+                std::mem::replace(encoder.get_mut(), vec![])
+            },
+        };
+
+        Ok(out)
+    }
+
+    /// Returns the rest of the compressed output.
+    pub fn finish(self) -> Result<Vec<u8>, Error> {
+        let out = match self {
+            Compressor::Zstd(encoder) => encoder.finish()?,
+            Compressor::Br(encoder) => encoder.finish()?,
+            Compressor::Gzip(encoder) => encoder.finish()?,
+        };
+
+        Ok(out)
+    }
+}
@@ -1,3 +1,5 @@
+use chrono::{NaiveDate, NaiveDateTime};
+
 #[derive(Debug)]
 pub struct DataFrame {
     pub columns: Vec<Column>,
@@ -30,6 +32,9 @@ impl DataFrame {
                 ColumnData::Float32(ref ns) => ns.len(),
                 ColumnData::Float64(ref ns) => ns.len(),
                 ColumnData::Text(ref ss) => ss.len(),
+                ColumnData::Date(ref ds) => ds.len(),
+                ColumnData::DateTime(ref ds) => ds.len(),
+                ColumnData::Decimal(ref ss) => ss.len(),
                 ColumnData::NullableInt8(ref ns) => ns.len(),
                 ColumnData::NullableInt16(ref ns) => ns.len(),
                 ColumnData::NullableInt32(ref ns) => ns.len(),
@@ -41,6 +46,31 @@ impl DataFrame {
                 ColumnData::NullableFloat32(ref ns) => ns.len(),
                 ColumnData::NullableFloat64(ref ns) => ns.len(),
                 ColumnData::NullableText(ref ss) => ss.len(),
+                ColumnData::NullableDate(ref ds) => ds.len(),
+                ColumnData::NullableDateTime(ref ds) => ds.len(),
+                ColumnData::NullableDecimal(ref ss) => ss.len(),
+                ColumnData::ArrayInt8(ref ns) => ns.len(),
+                ColumnData::ArrayInt16(ref ns) => ns.len(),
+                ColumnData::ArrayInt32(ref ns) => ns.len(),
+                ColumnData::ArrayInt64(ref ns) => ns.len(),
+                ColumnData::ArrayUInt8(ref ns) => ns.len(),
+                ColumnData::ArrayUInt16(ref ns) => ns.len(),
+                ColumnData::ArrayUInt32(ref ns) => ns.len(),
+                ColumnData::ArrayUInt64(ref ns) => ns.len(),
+                ColumnData::ArrayFloat32(ref ns) => ns.len(),
+                ColumnData::ArrayFloat64(ref ns) => ns.len(),
+                ColumnData::ArrayText(ref ns) => ns.len(),
+                ColumnData::NullableArrayInt8(ref ns) => ns.len(),
+                ColumnData::NullableArrayInt16(ref ns) => ns.len(),
+                ColumnData::NullableArrayInt32(ref ns) => ns.len(),
+                ColumnData::NullableArrayInt64(ref ns) => ns.len(),
+                ColumnData::NullableArrayUInt8(ref ns) => ns.len(),
+                ColumnData::NullableArrayUInt16(ref ns) => ns.len(),
+                ColumnData::NullableArrayUInt32(ref ns) => ns.len(),
+                ColumnData::NullableArrayUInt64(ref ns) => ns.len(),
+                ColumnData::NullableArrayFloat32(ref ns) => ns.len(),
+                ColumnData::NullableArrayFloat64(ref ns) => ns.len(),
+                ColumnData::NullableArrayText(ref ns) => ns.len(),
             }
         } else {
             0
@@ -80,6 +110,9 @@ pub enum ColumnData {
     Float32(Vec<f32>),
     Float64(Vec<f64>),
     Text(Vec<String>),
+    Date(Vec<NaiveDate>),
+    DateTime(Vec<NaiveDateTime>),
+    Decimal(Vec<String>), // kept as a string to be lossless
     NullableInt8(Vec<Option<i8>>),
     NullableInt16(Vec<Option<i16>>),
     NullableInt32(Vec<Option<i32>>),
@@ -91,4 +124,30 @@ pub enum ColumnData {
     NullableFloat32(Vec<Option<f32>>),
     NullableFloat64(Vec<Option<f64>>),
     NullableText(Vec<Option<String>>),
+    NullableDate(Vec<Option<NaiveDate>>),
+    NullableDateTime(Vec<Option<NaiveDateTime>>),
+    NullableDecimal(Vec<Option<String>>),
+    ArrayInt8(Vec<Vec<i8>>),
+    ArrayInt16(Vec<Vec<i16>>),
+    ArrayInt32(Vec<Vec<i32>>),
+    ArrayInt64(Vec<Vec<i64>>),
+    ArrayUInt8(Vec<Vec<u8>>),
+    ArrayUInt16(Vec<Vec<u16>>),
+    ArrayUInt32(Vec<Vec<u32>>),
+    ArrayUInt64(Vec<Vec<u64>>),
+    ArrayFloat32(Vec<Vec<f32>>),
+    ArrayFloat64(Vec<Vec<f64>>),
+    ArrayText(Vec<Vec<String>>),
+    /// both the array and its members can be null
+    NullableArrayInt8(Vec<Option<Vec<Option<i8>>>>),
+    NullableArrayInt16(Vec<Option<Vec<Option<i16>>>>),
+    NullableArrayInt32(Vec<Option<Vec<Option<i32>>>>),
+    NullableArrayInt64(Vec<Option<Vec<Option<i64>>>>),
+    NullableArrayUInt8(Vec<Option<Vec<Option<u8>>>>),
+    NullableArrayUInt16(Vec<Option<Vec<Option<u16>>>>),
+    NullableArrayUInt32(Vec<Option<Vec<Option<u32>>>>),
+    NullableArrayUInt64(Vec<Option<Vec<Option<u64>>>>),
+    NullableArrayFloat32(Vec<Option<Vec<Option<f32>>>>),
+    NullableArrayFloat64(Vec<Option<Vec<Option<f64>>>>),
+    NullableArrayText(Vec<Option<Vec<Option<String>>>>),
 }
@@ -1,5 +1,5 @@
 //! DB options: For now, only one db at a time, and only
-//! clickhouse or mysql
+//! clickhouse, postgres, mysql or sqlite
 //! They're set to conflict with each other in cli opts
 //!
 //! Also, casting to trait object:
@@ -19,6 +19,9 @@ use std::str::FromStr;
 
 use crate::backend::Backend;
 use crate::clickhouse::Clickhouse;
+use crate::mysql::MySql;
+use crate::postgres::Postgres;
+use crate::sqlite::Sqlite;
 
 /// from a full url e.g. clickhouse://127.0.0.1:9000 returns
 /// the db client, url, and database type.
@@ -44,14 +47,18 @@ pub fn get_db(db_url_full: &str) -> Result<(Box<dyn Backend + Send + Sync>, Stri
             Box::new(Clickhouse::from_url(&db_url)?) as
                 Box<dyn Backend + Send + Sync>
         },
-//        Database::MySql => {
-//            Box::new(MySql::from_addr(&db_url_full)?) as
-//                Box<dyn Backend + Send + Sync>
-//        },
-//        Database::Postgres => {
-//            Box::new(Postgres::from_addr(&db_url_full)?) as
-//                Box<dyn Backend + Send + Sync>
-//        },
+        Database::MySql => {
+            Box::new(MySql::from_addr(&db_url_full)?) as
+                Box<dyn Backend + Send + Sync>
+        },
+        Database::Postgres => {
+            Box::new(Postgres::from_addr(&db_url_full)?) as
+                Box<dyn Backend + Send + Sync>
+        },
+        Database::Sqlite => {
+            Box::new(Sqlite::from_path(&db_url)?) as
+                Box<dyn Backend + Send + Sync>
+        },
     };
 
     // Remove password when there's a user:password@host in the url
@@ -74,8 +81,9 @@ pub fn get_db(db_url_full: &str) -> Result<(Box<dyn Backend + Send + Sync>, Stri
 #[derive(Debug, Clone)]
 pub enum Database {
     Clickhouse,
-//    MySql,
-//    Postgres,
+    MySql,
+    Postgres,
+    Sqlite,
 }
 
 impl FromStr for Database {
@@ -84,8 +92,9 @@ impl FromStr for Database {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "clickhouse" => Ok(Database::Clickhouse),
-//            "mysql" => Ok(Database::MySql),
-//            "postgres" => Ok(Database::Postgres),
+            "mysql" => Ok(Database::MySql),
+            "postgres" | "postgresql" => Ok(Database::Postgres),
+            "sqlite" => Ok(Database::Sqlite),
             _ => Err(format_err!("database {} not supported or not parsed", s)),
         }
     }
@@ -95,8 +104,9 @@ impl fmt::Display for Database {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Database::Clickhouse => write!(f, "Clickhouse"),
-//            Database::MySql => write!(f, "MySql"),
-//            Database::Postgres => write!(f, "Postgres"),
+            Database::MySql => write!(f, "MySql"),
+            Database::Postgres => write!(f, "Postgres"),
+            Database::Sqlite => write!(f, "Sqlite"),
         }
     }
 }
@@ -1,17 +1,36 @@
+use arrow::ipc::writer::StreamWriter;
 use csv;
-use failure::{Error, format_err};
+use failure::{Error, bail, format_err};
 use indexmap::IndexMap;
-use serde::Serializer;
-use serde::ser::{SerializeSeq};
+use itertools::join;
+use parquet::arrow::ArrowWriter;
+use rust_xlsxwriter::Workbook;
+use serde_derive::Deserialize;
 use serde_json::{Value};
+use std::sync::Arc;
 
 use crate::dataframe::{DataFrame, ColumnData};
 
+mod record_batch;
+mod xlsx;
+
+use self::record_batch::{df_to_record_batch, empty_schema};
+
+/// Dates and datetimes are output as ISO-8601. Fractional seconds are
+/// only written when they're non-zero.
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
 #[derive(Debug, Clone)]
 pub enum FormatType{
     Csv,
+    Tsv,
     JsonRecords,
     JsonArrays,
+    JsonLines,
+    Parquet,
+    Arrow,
+    Xlsx,
 }
 
 impl std::str::FromStr for FormatType {
@@ -20,218 +39,680 @@ impl std::str::FromStr for FormatType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "csv" => Ok(FormatType::Csv),
+            "tsv" => Ok(FormatType::Tsv),
             "jsonrecords" => Ok(FormatType::JsonRecords),
             "jsonarrays" => Ok(FormatType::JsonArrays),
+            "jsonl" | "jsonlines" => Ok(FormatType::JsonLines),
+            "parquet" => Ok(FormatType::Parquet),
+            "arrow" => Ok(FormatType::Arrow),
+            "xlsx" => Ok(FormatType::Xlsx),
             _ => Err(format_err!("{} is not a supported format", s)),
         }
     }
 }
 
-/// Wrapper to format `DataFrame` to the desired output format.
-pub fn format_records(headers: &[String], df: DataFrame, format_type: FormatType) -> Result<String, Error> {
-    match format_type {
-        FormatType::Csv => Ok(format_csv(headers, df)?),
-        FormatType::JsonRecords => Ok(format_jsonrecords(headers, df)?),
-        FormatType::JsonArrays => Ok(format_jsonarrays(headers, df)?),
+impl FormatType {
+    /// Picks the format for an `Accept` header: the first of the media
+    /// ranges with the highest quality that names a supported format.
+    /// `*/*` is csv, the default format.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        let mut best: Option<(f32, FormatType)> = None;
+
+        for media_range in accept.split(',') {
+            let mut parts = media_range.split(';');
+
+            let media_type = parts.next()
+                .unwrap_or_default()
+                .trim()
+                .to_lowercase();
+
+            let quality = parts
+                .map(|param| param.trim())
+                .find(|param| param.starts_with("q="))
+                .map(|param| param[2..].parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+
+            // q=0 means not acceptable
+            if quality <= 0.0 {
+                continue;
+            }
+
+            let format_type = match FormatType::from_media_type(&media_type) {
+                Some(f) => f,
+                None => continue,
+            };
+
+            if best.as_ref().map(|(q, _)| quality > *q).unwrap_or(true) {
+                best = Some((quality, format_type));
+            }
+        }
+
+        best.map(|(_, format_type)| format_type)
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "*/*" | "text/*" | "text/csv" => Some(FormatType::Csv),
+            "text/tab-separated-values" => Some(FormatType::Tsv),
+            "application/*" | "application/json" => Some(FormatType::JsonRecords),
+            "application/x-ndjson" | "application/jsonl" | "application/jsonlines" => Some(FormatType::JsonLines),
+            "application/vnd.apache.parquet" | "application/x-parquet" => Some(FormatType::Parquet),
+            "application/vnd.apache.arrow.stream" => Some(FormatType::Arrow),
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => Some(FormatType::Xlsx),
+            _ => None,
+        }
+    }
+
+    /// Parquet and xlsx are already compressed, so compressing the
+    /// response again gains little.
+    pub fn is_compressible(&self) -> bool {
+        match self {
+            FormatType::Parquet | FormatType::Xlsx => false,
+            _ => true,
+        }
+    }
+
+    /// The most rows a response in this format can have, if limited.
+    pub fn max_rows(&self) -> Option<u64> {
+        match self {
+            FormatType::Xlsx => Some(xlsx::MAX_ROWS),
+            _ => None,
+        }
+    }
+
+    /// The media type of a response in this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            FormatType::Csv => "text/csv; charset=utf-8",
+            FormatType::Tsv => "text/tab-separated-values; charset=utf-8",
+            FormatType::JsonRecords => "application/json",
+            FormatType::JsonArrays => "application/json",
+            FormatType::JsonLines => "application/x-ndjson",
+            FormatType::Parquet => "application/vnd.apache.parquet",
+            FormatType::Arrow => "application/vnd.apache.arrow.stream",
+            FormatType::Xlsx => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        }
     }
 }
 
-/// Formats response `DataFrame` to CSV.
-fn format_csv(headers: &[String], df: DataFrame) -> Result<String, Error> {
-    let mut wtr = csv::WriterBuilder::new()
-        .from_writer(vec![]);
-
-    // write header
-    wtr.write_record(headers)?;
-
-    let mut row_buf = vec![];
-
-    // write data
-    for row_idx in 0..df.len() {
-        for col_idx in 0..df.columns.len() {
-            let val = match df.columns[col_idx].column_data {
-                ColumnData::Int8(ref ns) =>    ns[row_idx].to_string(),
-                ColumnData::Int16(ref ns) =>   ns[row_idx].to_string(),
-                ColumnData::Int32(ref ns) =>   ns[row_idx].to_string(),
-                ColumnData::Int64(ref ns) =>   ns[row_idx].to_string(),
-                ColumnData::UInt8(ref ns) =>   ns[row_idx].to_string(),
-                ColumnData::UInt16(ref ns) =>  ns[row_idx].to_string(),
-                ColumnData::UInt32(ref ns) =>  ns[row_idx].to_string(),
-                ColumnData::UInt64(ref ns) =>  ns[row_idx].to_string(),
-                ColumnData::Float32(ref ns) => ns[row_idx].to_string(),
-                ColumnData::Float64(ref ns) => ns[row_idx].to_string(),
-                ColumnData::Text(ref ss) =>    ss[row_idx].to_string(),
-                ColumnData::NullableInt8(ref ns) =>    ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableInt16(ref ns) =>   ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableInt32(ref ns) =>   ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableInt64(ref ns) =>   ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableUInt8(ref ns) =>   ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableUInt16(ref ns) =>  ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableUInt32(ref ns) =>  ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableUInt64(ref ns) =>  ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableFloat32(ref ns) => ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableFloat64(ref ns) => ns[row_idx].map(|n| n.to_string()).unwrap_or("".into()),
-                ColumnData::NullableText(ref ss) =>    ss[row_idx].clone().unwrap_or("".into()),
-            };
+/// Options for the csv and tsv formats, as set in the schema (an
+/// endpoint's `csv`) or in the query (`csv_delimiter`, etc.). Unset
+/// options fall back to the defaults of `CsvDialect`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CsvOptions {
+    pub delimiter: Option<String>,
+    pub quote: Option<String>,
+    pub header: Option<bool>,
+    pub null: Option<String>,
+    pub bom: Option<bool>,
+}
 
-            row_buf.push(val);
+impl CsvOptions {
+    /// Options that are set in `self` take precedence over `other`.
+    pub fn or(self, other: CsvOptions) -> CsvOptions {
+        CsvOptions {
+            delimiter: self.delimiter.or(other.delimiter),
+            quote: self.quote.or(other.quote),
+            header: self.header.or(other.header),
+            null: self.null.or(other.null),
+            bom: self.bom.or(other.bom),
         }
-        wtr.write_record(&row_buf)?;
+    }
+}
+
+/// How csv and tsv are written.
+///
+/// Defaults are a comma (tab for tsv) delimiter, quotes only when
+/// necessary, a header row, nulls as empty fields, and no BOM.
+#[derive(Debug, Clone)]
+pub struct CsvDialect {
+    delimiter: u8,
+    quote_style: csv::QuoteStyle,
+    has_header: bool,
+    null: String,
+    has_bom: bool,
+}
 
-        row_buf.clear();
+impl CsvDialect {
+    pub fn from_options(options: &CsvOptions, format_type: &FormatType) -> Result<Self, Error> {
+        let delimiter = match options.delimiter.as_ref().map(|d| d.as_str()) {
+            Some("tab") | Some("\\t") | Some("\t") => b'\t',
+            Some(d) if d.len() == 1 && d != "\"" && d != "\n" && d != "\r" => d.as_bytes()[0],
+            Some(d) => bail!("csv delimiter must be a single ascii char or \"tab\", not '{}'", d),
+            None => match format_type {
+                FormatType::Tsv => b'\t',
+                _ => b',',
+            },
+        };
+
+        let quote_style = match options.quote.as_ref().map(|q| q.as_str()) {
+            Some("necessary") | None => csv::QuoteStyle::Necessary,
+            Some("always") => csv::QuoteStyle::Always,
+            Some("non_numeric") => csv::QuoteStyle::NonNumeric,
+            Some("never") => csv::QuoteStyle::Never,
+            Some(q) => bail!("csv quote must be one of necessary, always, non_numeric, never, not '{}'", q),
+        };
+
+        Ok(CsvDialect {
+            delimiter,
+            quote_style,
+            has_header: options.header.unwrap_or(true),
+            null: options.null.clone().unwrap_or_default(),
+            has_bom: options.bom.unwrap_or(false),
+        })
     }
 
-    let res = String::from_utf8(wtr.into_inner()?)?;
+    fn writer(&self) -> csv::Writer<Vec<u8>> {
+        csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .quote_style(self.quote_style)
+            .from_writer(vec![])
+    }
+}
 
-    Ok(res)
+/// Formats `DataFrame`s to the desired output format, one chunk at a time,
+/// so that a response can be streamed.
+///
+/// The output is `head`, then `rows` for each chunk, then `tail`.
+///
+/// `array_delimiter` joins the members of array columns in CSV; JSON
+/// formats output arrays as JSON arrays. `csv_dialect` is only used by
+/// csv and tsv.
+///
+/// Decimals are output as strings in JSON, so that clients don't parse
+/// them as (lossy) floats.
+///
+/// `name` (the endpoint) names the worksheet in xlsx.
+///
+/// `count`, the total rows matching the query when requested, and
+/// `next_cursor`, for a page of keyset pagination, are included in the
+/// envelope of jsonrecords and jsonarrays.
+pub struct RecordsFormatter {
+    name: String,
+    headers: Vec<String>,
+    format_type: FormatType,
+    array_delimiter: String,
+    csv_dialect: CsvDialect,
+    count: Option<u64>,
+    // `Some(None)` when it's the last page
+    next_cursor: Option<Option<String>>,
+    is_first_row: bool,
+    // created with the schema of the first chunk
+    parquet_writer: Option<ArrowWriter<Vec<u8>>>,
+    arrow_writer: Option<StreamWriter<Vec<u8>>>,
+    // an xlsx file is a zip, so the workbook is only written out at the end
+    xlsx_workbook: Option<Workbook>,
+    xlsx_next_row: u32,
 }
 
-/// Formats response `DataFrame` to JSON records.
-fn format_jsonrecords(headers: &[String], df: DataFrame) -> Result<String, Error> {
-    // use streaming serializer
-    // Necessary because this way we don't create a huge vec of rows containing Value
-    // (very expensive)
-
-    // I've ended up doing this a bit more manually than I want; but I don't know how
-    // to easily move between manually calling serializing methods, and having some
-    // done more automatically. For example, the rows have to be serialized using
-    // seq, but I can't easily call serialize_data including those rows, since I'm using
-    // a manual method different from the serialize_data.
-
-    // I had a hard time figuring out how to serialize the struct before the data.
-    // So I just wrote the bytes in and put a '}' at the end, and serialized
-    // the values in between.
-    let mut ser = serde_json::Serializer::new(
-        b"{\"data\":".to_vec()
-    );
-
-    let mut seq = ser.serialize_seq(Some(df.len()))?;
-
-
-    // write data
-    for row_idx in 0..df.len() {
-        let mut row: IndexMap<&str, serde_json::Value> = IndexMap::new();
-        for col_idx in 0..df.columns.len() {
-            let val = match df.columns[col_idx].column_data {
-                ColumnData::Int8(ref ns) =>    ns[row_idx].clone().into(),
-                ColumnData::Int16(ref ns) =>   ns[row_idx].clone().into(),
-                ColumnData::Int32(ref ns) =>   ns[row_idx].clone().into(),
-                ColumnData::Int64(ref ns) =>   ns[row_idx].clone().into(),
-                ColumnData::UInt8(ref ns) =>   ns[row_idx].clone().into(),
-                ColumnData::UInt16(ref ns) =>  ns[row_idx].clone().into(),
-                ColumnData::UInt32(ref ns) =>  ns[row_idx].clone().into(),
-                ColumnData::UInt64(ref ns) =>  ns[row_idx].clone().into(),
-                ColumnData::Float32(ref ns) => ns[row_idx].clone().into(),
-                ColumnData::Float64(ref ns) => ns[row_idx].clone().into(),
-                ColumnData::Text(ref ss) =>    ss[row_idx].clone().into(),
-                ColumnData::NullableInt8(ref ns) =>    ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableInt16(ref ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableInt32(ref ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableInt64(ref ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableUInt8(ref ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableUInt16(ref ns) =>  ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableUInt32(ref ns) =>  ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableUInt64(ref ns) =>  ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableFloat32(ref ns) => ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableFloat64(ref ns) => ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableText(ref ss) =>    ss[row_idx].clone().map(|n| n.into()).unwrap_or(Value::Null),
-            };
+impl RecordsFormatter {
+    pub fn new(
+        name: String,
+        headers: Vec<String>,
+        format_type: FormatType,
+        array_delimiter: String,
+        csv_dialect: CsvDialect,
+        ) -> Self
+    {
+        RecordsFormatter {
+            name,
+            headers,
+            format_type,
+            array_delimiter,
+            csv_dialect,
+            count: None,
+            next_cursor: None,
+            is_first_row: true,
+            parquet_writer: None,
+            arrow_writer: None,
+            xlsx_workbook: None,
+            // the first row is the headers
+            xlsx_next_row: 1,
+        }
+    }
+
+    /// Must be set before the head is output.
+    pub fn set_count(&mut self, count: u64) {
+        self.count = Some(count);
+    }
+
+    /// Must be set before the head is output.
+    pub fn set_next_cursor(&mut self, next_cursor: Option<String>) {
+        self.next_cursor = Some(next_cursor);
+    }
 
-            row.insert(&headers[col_idx], val);
+    /// Output before any rows.
+    pub fn head(&self) -> Result<Vec<u8>, Error> {
+        match self.format_type {
+            FormatType::Csv | FormatType::Tsv => {
+                let mut res = vec![];
+
+                if self.csv_dialect.has_bom {
+                    res.extend(b"\xEF\xBB\xBF");
+                }
+
+                if self.csv_dialect.has_header {
+                    let mut wtr = self.csv_dialect.writer();
+                    wtr.write_record(&self.headers)?;
+                    res.extend(wtr.into_inner()?);
+                }
+
+                Ok(res)
+            },
+            FormatType::JsonRecords => {
+                let mut res = b"{".to_vec();
+                if let Some(count) = self.count {
+                    res.extend(format!("\"count\":{},", count).as_bytes());
+                }
+                if let Some(ref next_cursor) = self.next_cursor {
+                    res.extend(b"\"next_cursor\":");
+                    serde_json::to_writer(&mut res, next_cursor)?;
+                    res.extend(b",");
+                }
+                res.extend(b"\"data\":[");
+
+                Ok(res)
+            },
+            FormatType::JsonArrays => {
+                let mut res = b"{\"headers\":".to_vec();
+                serde_json::to_writer(&mut res, &self.headers)?;
+                if let Some(count) = self.count {
+                    res.extend(format!(",\"count\":{}", count).as_bytes());
+                }
+                if let Some(ref next_cursor) = self.next_cursor {
+                    res.extend(b",\"next_cursor\":");
+                    serde_json::to_writer(&mut res, next_cursor)?;
+                }
+                res.extend(b",\"data\":[");
+
+                Ok(res)
+            },
+            FormatType::JsonLines => Ok(vec![]),
+            // parquet's header is written with the first row group
+            FormatType::Parquet => Ok(vec![]),
+            // the arrow schema message is written with the first batch
+            FormatType::Arrow => Ok(vec![]),
+            FormatType::Xlsx => Ok(vec![]),
         }
+    }
 
-        seq.serialize_element(&row)?;
+    /// Output for a chunk of rows.
+    pub fn rows(&mut self, df: &DataFrame) -> Result<Vec<u8>, Error> {
+        match self.format_type {
+            FormatType::Csv | FormatType::Tsv => self.csv_rows(df),
+            FormatType::JsonRecords => self.jsonrecords_rows(df),
+            FormatType::JsonArrays => self.jsonarrays_rows(df),
+            FormatType::JsonLines => self.jsonlines_rows(df),
+            FormatType::Parquet => self.parquet_rows(df),
+            FormatType::Arrow => self.arrow_rows(df),
+            FormatType::Xlsx => self.xlsx_rows(df),
+        }
     }
 
-    seq.end()?;
-    let mut res = String::from_utf8(ser.into_inner())?;
-    res.push('}');
-    Ok(res)
+    /// Output after all rows.
+    pub fn tail(&mut self) -> Result<Vec<u8>, Error> {
+        match self.format_type {
+            FormatType::Csv | FormatType::Tsv => Ok(vec![]),
+            FormatType::JsonRecords => Ok(b"]}".to_vec()),
+            FormatType::JsonArrays => Ok(b"]}".to_vec()),
+            FormatType::JsonLines => Ok(vec![]),
+            FormatType::Parquet => {
+                let writer = match self.parquet_writer.take() {
+                    Some(writer) => writer,
+                    None => {
+                        // no rows, so there's no DataFrame to take the schema from
+                        let schema = Arc::new(empty_schema(&self.headers));
+                        ArrowWriter::try_new(vec![], schema, None)?
+                    },
+                };
+
+                // writes any buffered row group, and the footer
+                Ok(writer.into_inner()?)
+            },
+            FormatType::Arrow => {
+                let writer = match self.arrow_writer.take() {
+                    Some(writer) => writer,
+                    None => StreamWriter::try_new(vec![], &empty_schema(&self.headers))?,
+                };
+
+                // writes the end-of-stream marker
+                Ok(writer.into_inner()?)
+            },
+            FormatType::Xlsx => {
+                let mut workbook = match self.xlsx_workbook.take() {
+                    Some(workbook) => workbook,
+                    None => xlsx::new_workbook(&self.name, &self.headers)?,
+                };
+
+                Ok(workbook.save_to_buffer()?)
+            },
+        }
+    }
 
-//    let res = json!({
-//        "data": rows,
-//    });
-//
-//    Ok(res.to_string())
-}
+    fn csv_rows(&mut self, df: &DataFrame) -> Result<Vec<u8>, Error> {
+        let mut wtr = self.csv_dialect.writer();
 
-/// Formats response `DataFrame` to JSON arrays.
-fn format_jsonarrays(headers: &[String], df: DataFrame) -> Result<String, Error> {
-    // use streaming serializer
-    // Necessary because this way we don't create a huge vec of rows containing Value
-    // (very expensive)
-
-    // I've ended up doing this a bit more manually than I want; but I don't know how
-    // to easily move between manually calling serializing methods, and having some
-    // done more automatically. For example, the rows have to be serialized using
-    // seq, but I can't easily call serialize_data including those rows, since I'm using
-    // a manual method different from the serialize_data.
-
-    // serialize headers
-    let mut ser = serde_json::Serializer::new(
-        b"{\"headers\":".to_vec()
-    );
-    let mut seq_headers = ser.serialize_seq(Some(headers.len()))?;
-
-    for header in headers {
-        seq_headers.serialize_element(&header)?;
-    }
-    seq_headers.end()?;
-
-
-    // now the data
-    let mut intermediate = ser.into_inner();
-    intermediate.extend(b",\"data\":");
-
-
-    let mut ser = serde_json::Serializer::new(intermediate);
-    let mut seq_data = ser.serialize_seq(Some(df.len()))?;
-
-
-    // then write data
-    for row_idx in 0..df.len() {
-        let mut row: Vec<serde_json::Value> = vec![];
-        for col_idx in 0..df.columns.len() {
-            let val = match df.columns[col_idx].column_data {
-                ColumnData::Int8(ref ns) =>    ns[row_idx].clone().into(),
-                ColumnData::Int16(ref ns) =>   ns[row_idx].clone().into(),
-                ColumnData::Int32(ref ns) =>   ns[row_idx].clone().into(),
-                ColumnData::Int64(ref ns) =>   ns[row_idx].clone().into(),
-                ColumnData::UInt8(ref ns) =>   ns[row_idx].clone().into(),
-                ColumnData::UInt16(ref ns) =>  ns[row_idx].clone().into(),
-                ColumnData::UInt32(ref ns) =>  ns[row_idx].clone().into(),
-                ColumnData::UInt64(ref ns) =>  ns[row_idx].clone().into(),
-                ColumnData::Float32(ref ns) => ns[row_idx].clone().into(),
-                ColumnData::Float64(ref ns) => ns[row_idx].clone().into(),
-                ColumnData::Text(ref ss) =>    ss[row_idx].clone().into(),
-                ColumnData::NullableInt8(ref ns) =>    ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableInt16(ref ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableInt32(ref ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableInt64(ref ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableUInt8(ref ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableUInt16(ref ns) =>  ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableUInt32(ref ns) =>  ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableUInt64(ref ns) =>  ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableFloat32(ref ns) => ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableFloat64(ref ns) => ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
-                ColumnData::NullableText(ref ss) =>    ss[row_idx].clone().map(|n| n.into()).unwrap_or(Value::Null),
-            };
+        let mut row_buf = vec![];
 
-            row.push(val);
+        for row_idx in 0..df.len() {
+            for col_idx in 0..df.columns.len() {
+                let val = csv_value(&df.columns[col_idx].column_data, row_idx, &self.array_delimiter, &self.csv_dialect.null);
+
+                row_buf.push(val);
+            }
+            wtr.write_record(&row_buf)?;
+
+            row_buf.clear();
         }
 
-        seq_data.serialize_element(&row)?;;
+        Ok(wtr.into_inner()?)
     }
 
-    seq_data.end()?;
+    fn jsonrecords_rows(&mut self, df: &DataFrame) -> Result<Vec<u8>, Error> {
+        let mut res = vec![];
+
+        for row_idx in 0..df.len() {
+            self.write_separator(&mut res);
+
+            let mut row: IndexMap<&str, serde_json::Value> = IndexMap::new();
+            for col_idx in 0..df.columns.len() {
+                let val = json_value(&df.columns[col_idx].column_data, row_idx);
 
-    // now take out vec, convert to string, and return
-    let mut res = String::from_utf8(ser.into_inner())?;
-    res.push('}');
-    Ok(res)
+                row.insert(&self.headers[col_idx], val);
+            }
 
-//    let res = json!({
-//        "headers": headers,
-//        "data": rows,
-//    });
+            serde_json::to_writer(&mut res, &row)?;
+        }
+
+        Ok(res)
+    }
+
+    fn jsonarrays_rows(&mut self, df: &DataFrame) -> Result<Vec<u8>, Error> {
+        let mut res = vec![];
+
+        for row_idx in 0..df.len() {
+            self.write_separator(&mut res);
+
+            let mut row: Vec<serde_json::Value> = vec![];
+            for col_idx in 0..df.columns.len() {
+                let val = json_value(&df.columns[col_idx].column_data, row_idx);
+
+                row.push(val);
+            }
+
+            serde_json::to_writer(&mut res, &row)?;
+        }
+
+        Ok(res)
+    }
+
+    /// Newline-delimited JSON, one record per line.
+    fn jsonlines_rows(&mut self, df: &DataFrame) -> Result<Vec<u8>, Error> {
+        let mut res = vec![];
+
+        for row_idx in 0..df.len() {
+            let mut row: IndexMap<&str, serde_json::Value> = IndexMap::new();
+            for col_idx in 0..df.columns.len() {
+                let val = json_value(&df.columns[col_idx].column_data, row_idx);
+
+                row.insert(&self.headers[col_idx], val);
+            }
+
+            serde_json::to_writer(&mut res, &row)?;
+            res.push(b'\n');
+        }
+
+        Ok(res)
+    }
+
+    /// Each chunk is flushed as its own row group, so that the writer
+    /// doesn't buffer the whole result.
+    fn parquet_rows(&mut self, df: &DataFrame) -> Result<Vec<u8>, Error> {
+        let batch = df_to_record_batch(&self.headers, df)?;
+
+        let writer = match self.parquet_writer {
+            Some(ref mut writer) => writer,
+            None => {
+                let writer = ArrowWriter::try_new(vec![], batch.schema(), None)?;
+                self.parquet_writer.get_or_insert(writer)
+            },
+        };
+
+        writer.write(&batch)?;
+        writer.flush()?;
+
+        Ok(std::mem::replace(writer.inner_mut(), vec![]))
+    }
+
+    /// Each chunk is written as one record batch of the arrow IPC stream.
+    fn arrow_rows(&mut self, df: &DataFrame) -> Result<Vec<u8>, Error> {
+        let batch = df_to_record_batch(&self.headers, df)?;
+
+        let writer = match self.arrow_writer {
+            Some(ref mut writer) => writer,
+            None => {
+                let writer = StreamWriter::try_new(vec![], &batch.schema())?;
+                self.arrow_writer.get_or_insert(writer)
+            },
+        };
+
+        writer.write(&batch)?;
+
+        Ok(std::mem::replace(writer.get_mut(), vec![]))
+    }
+
+    /// Rows are added to the worksheet, and nothing is output until `tail`.
+    fn xlsx_rows(&mut self, df: &DataFrame) -> Result<Vec<u8>, Error> {
+        let workbook = match self.xlsx_workbook {
+            Some(ref mut workbook) => workbook,
+            None => {
+                let workbook = xlsx::new_workbook(&self.name, &self.headers)?;
+                self.xlsx_workbook.get_or_insert(workbook)
+            },
+        };
+
+        let worksheet = workbook.worksheet_from_index(0)?;
+        xlsx::write_df(worksheet, df, self.xlsx_next_row, &self.array_delimiter)?;
+
+        self.xlsx_next_row += df.len() as u32;
+
+        Ok(vec![])
+    }
+
+    /// Rows in a json array are separated by commas; the first row in the
+    /// response (not just in the chunk) has none.
+    fn write_separator(&mut self, buf: &mut Vec<u8>) {
+        if self.is_first_row {
+            self.is_first_row = false;
+        } else {
+            buf.push(b',');
+        }
+    }
+}
+
+/// Converts a value from a `ColumnData` to a csv field.
+fn csv_value(column_data: &ColumnData, row_idx: usize, array_delimiter: &str, null: &str) -> String {
+    match column_data {
+        ColumnData::Int8(ns) =>    ns[row_idx].to_string(),
+        ColumnData::Int16(ns) =>   ns[row_idx].to_string(),
+        ColumnData::Int32(ns) =>   ns[row_idx].to_string(),
+        ColumnData::Int64(ns) =>   ns[row_idx].to_string(),
+        ColumnData::UInt8(ns) =>   ns[row_idx].to_string(),
+        ColumnData::UInt16(ns) =>  ns[row_idx].to_string(),
+        ColumnData::UInt32(ns) =>  ns[row_idx].to_string(),
+        ColumnData::UInt64(ns) =>  ns[row_idx].to_string(),
+        ColumnData::Float32(ns) => ns[row_idx].to_string(),
+        ColumnData::Float64(ns) => ns[row_idx].to_string(),
+        ColumnData::Text(ss) =>    ss[row_idx].to_string(),
+        ColumnData::Date(ds) =>    ds[row_idx].format(DATE_FORMAT).to_string(),
+        ColumnData::DateTime(ds) => ds[row_idx].format(DATETIME_FORMAT).to_string(),
+        ColumnData::Decimal(ss) =>  ss[row_idx].to_string(),
+        ColumnData::NullableInt8(ns) =>    ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableInt16(ns) =>   ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableInt32(ns) =>   ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableInt64(ns) =>   ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableUInt8(ns) =>   ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableUInt16(ns) =>  ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableUInt32(ns) =>  ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableUInt64(ns) =>  ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableFloat32(ns) => ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableFloat64(ns) => ns[row_idx].map(|n| n.to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableText(ss) =>    ss[row_idx].clone().unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableDate(ds) =>    ds[row_idx].map(|d| d.format(DATE_FORMAT).to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableDateTime(ds) => ds[row_idx].map(|d| d.format(DATETIME_FORMAT).to_string()).unwrap_or_else(|| null.to_owned()),
+        ColumnData::NullableDecimal(ss) =>  ss[row_idx].clone().unwrap_or_else(|| null.to_owned()),
+        ColumnData::ArrayInt8(ns) =>     join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayInt16(ns) =>    join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayInt32(ns) =>    join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayInt64(ns) =>    join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayUInt8(ns) =>    join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayUInt16(ns) =>   join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayUInt32(ns) =>   join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayUInt64(ns) =>   join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayFloat32(ns) =>  join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayFloat64(ns) =>  join(&ns[row_idx], array_delimiter),
+        ColumnData::ArrayText(ns) =>     join(&ns[row_idx], array_delimiter),
+        ColumnData::NullableArrayInt8(ns) =>     nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayInt16(ns) =>    nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayInt32(ns) =>    nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayInt64(ns) =>    nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayUInt8(ns) =>    nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayUInt16(ns) =>   nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayUInt32(ns) =>   nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayUInt64(ns) =>   nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayFloat32(ns) =>  nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayFloat64(ns) =>  nullable_array_csv(&ns[row_idx], array_delimiter, null),
+        ColumnData::NullableArrayText(ns) =>     nullable_array_csv(&ns[row_idx], array_delimiter, null),
+    }
+}
+
+/// A null array is written as `null`, and so are null members.
+fn nullable_array_csv<T: ToString>(members: &Option<Vec<Option<T>>>, array_delimiter: &str, null: &str) -> String {
+    match members {
+        Some(members) => {
+            let members = members.iter()
+                .map(|m| m.as_ref().map(|m| m.to_string()).unwrap_or_else(|| null.to_owned()));
+
+            join(members, array_delimiter)
+        },
+        None => null.to_owned(),
+    }
+}
+
+/// Converts a value from a `ColumnData` to json.
+fn json_value(column_data: &ColumnData, row_idx: usize) -> Value {
+    match column_data {
+        ColumnData::Int8(ns) =>    ns[row_idx].clone().into(),
+        ColumnData::Int16(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::Int32(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::Int64(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::UInt8(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::UInt16(ns) =>  ns[row_idx].clone().into(),
+        ColumnData::UInt32(ns) =>  ns[row_idx].clone().into(),
+        ColumnData::UInt64(ns) =>  ns[row_idx].clone().into(),
+        ColumnData::Float32(ns) => ns[row_idx].clone().into(),
+        ColumnData::Float64(ns) => ns[row_idx].clone().into(),
+        ColumnData::Text(ss) =>    ss[row_idx].clone().into(),
+        ColumnData::Date(ds) =>    ds[row_idx].format(DATE_FORMAT).to_string().into(),
+        ColumnData::DateTime(ds) => ds[row_idx].format(DATETIME_FORMAT).to_string().into(),
+        ColumnData::Decimal(ss) =>  ss[row_idx].clone().into(),
+        ColumnData::NullableInt8(ns) =>    ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableInt16(ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableInt32(ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableInt64(ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableUInt8(ns) =>   ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableUInt16(ns) =>  ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableUInt32(ns) =>  ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableUInt64(ns) =>  ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableFloat32(ns) => ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableFloat64(ns) => ns[row_idx].map(|n| n.clone().into()).unwrap_or(Value::Null),
+        ColumnData::NullableText(ss) =>    ss[row_idx].clone().map(|n| n.into()).unwrap_or(Value::Null),
+        ColumnData::NullableDate(ds) =>    ds[row_idx].map(|d| d.format(DATE_FORMAT).to_string().into()).unwrap_or(Value::Null),
+        ColumnData::NullableDateTime(ds) => ds[row_idx].map(|d| d.format(DATETIME_FORMAT).to_string().into()).unwrap_or(Value::Null),
+        ColumnData::NullableDecimal(ss) =>  ss[row_idx].clone().map(|n| n.into()).unwrap_or(Value::Null),
+        ColumnData::ArrayInt8(ns) =>     ns[row_idx].clone().into(),
+        ColumnData::ArrayInt16(ns) =>    ns[row_idx].clone().into(),
+        ColumnData::ArrayInt32(ns) =>    ns[row_idx].clone().into(),
+        ColumnData::ArrayInt64(ns) =>    ns[row_idx].clone().into(),
+        ColumnData::ArrayUInt8(ns) =>    ns[row_idx].clone().into(),
+        ColumnData::ArrayUInt16(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::ArrayUInt32(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::ArrayUInt64(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::ArrayFloat32(ns) =>  ns[row_idx].clone().into(),
+        ColumnData::ArrayFloat64(ns) =>  ns[row_idx].clone().into(),
+        ColumnData::ArrayText(ns) =>     ns[row_idx].clone().into(),
+        ColumnData::NullableArrayInt8(ns) =>     ns[row_idx].clone().into(),
+        ColumnData::NullableArrayInt16(ns) =>    ns[row_idx].clone().into(),
+        ColumnData::NullableArrayInt32(ns) =>    ns[row_idx].clone().into(),
+        ColumnData::NullableArrayInt64(ns) =>    ns[row_idx].clone().into(),
+        ColumnData::NullableArrayUInt8(ns) =>    ns[row_idx].clone().into(),
+        ColumnData::NullableArrayUInt16(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::NullableArrayUInt32(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::NullableArrayUInt64(ns) =>   ns[row_idx].clone().into(),
+        ColumnData::NullableArrayFloat32(ns) =>  ns[row_idx].clone().into(),
+        ColumnData::NullableArrayFloat64(ns) =>  ns[row_idx].clone().into(),
+        ColumnData::NullableArrayText(ns) =>     ns[row_idx].clone().into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use arrow::array::{Array, ListArray};
+    use serde_json::json;
+
+    use super::*;
+    use crate::dataframe::Column;
+
+    fn nullable_arrays() -> DataFrame {
+        DataFrame::from_vec(vec![
+            Column::new("ns".to_owned(), ColumnData::NullableArrayInt32(vec![
+                Some(vec![Some(1), None]),
+                None,
+                Some(vec![]),
+            ])),
+            Column::new("ss".to_owned(), ColumnData::NullableArrayText(vec![
+                Some(vec![Some("a".to_owned()), None]),
+                None,
+                Some(vec![]),
+            ])),
+        ])
+    }
+
+    #[test]
+    fn nullable_array_csv() {
+        let df = nullable_arrays();
+
+        let values: Vec<_> = (0..3)
+            .map(|row_idx| csv_value(&df.columns[0].column_data, row_idx, ",", "NULL"))
+            .collect();
+
+        assert_eq!(values, vec!["1,NULL", "NULL", ""]);
+    }
+
+    #[test]
+    fn nullable_array_json() {
+        let df = nullable_arrays();
+
+        let values: Vec<_> = (0..3)
+            .map(|row_idx| json_value(&df.columns[1].column_data, row_idx))
+            .collect();
+
+        assert_eq!(values, vec![json!(["a", null]), Value::Null, json!([])]);
+    }
+
+    #[test]
+    fn nullable_array_record_batch() {
+        let headers = vec!["ns".to_owned(), "ss".to_owned()];
+        let batch = df_to_record_batch(&headers, &nullable_arrays()).unwrap();
+
+        for col_idx in 0..2 {
+            let list = batch.column(col_idx).as_any().downcast_ref::<ListArray>().unwrap();
+
+            assert!(batch.schema().field(col_idx).is_nullable());
+            assert_eq!(list.len(), 3);
+            assert!(list.is_null(1));
+            assert_eq!(list.value(0).null_count(), 1);
+            assert_eq!(list.value(2).len(), 0);
+        }
+    }
 }
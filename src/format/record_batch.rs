@@ -0,0 +1,178 @@
+//! Convert DataFrame to an arrow RecordBatch, for the parquet (and arrow) formats.
+//!
+//! Column types follow the `ColumnData` variants, and only `Nullable*`
+//! variants give nullable fields. Decimals are kept as strings, since
+//! their precision and scale aren't known once they're in a DataFrame.
+
+use arrow::array::{
+    ArrayRef,
+    Date32Array,
+    Float32Array,
+    Float64Array,
+    Int8Array,
+    Int16Array,
+    Int32Array,
+    Int64Array,
+    ListArray,
+    ListBuilder,
+    StringArray,
+    StringBuilder,
+    TimestampMicrosecondArray,
+    UInt8Array,
+    UInt16Array,
+    UInt32Array,
+    UInt64Array,
+};
+use arrow::datatypes::{
+    DataType,
+    Field,
+    Float32Type,
+    Float64Type,
+    Int8Type,
+    Int16Type,
+    Int32Type,
+    Int64Type,
+    Schema,
+    UInt8Type,
+    UInt16Type,
+    UInt32Type,
+    UInt64Type,
+};
+use arrow::record_batch::RecordBatch;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use failure::Error;
+use std::sync::Arc;
+
+use crate::dataframe::{DataFrame, ColumnData};
+
+/// Days from 0001-01-01 (chrono's day 1) to 1970-01-01.
+const UNIX_EPOCH_DAYS_FROM_CE: i32 = 719_163;
+
+pub fn df_to_record_batch(headers: &[String], df: &DataFrame) -> Result<RecordBatch, Error> {
+    let mut fields = vec![];
+    let mut arrays = vec![];
+
+    for (header, col) in headers.iter().zip(&df.columns) {
+        let (array, is_nullable): (ArrayRef, bool) = match col.column_data {
+            ColumnData::Int8(ref ns) => (Arc::new(Int8Array::from(ns.clone())), false),
+            ColumnData::Int16(ref ns) => (Arc::new(Int16Array::from(ns.clone())), false),
+            ColumnData::Int32(ref ns) => (Arc::new(Int32Array::from(ns.clone())), false),
+            ColumnData::Int64(ref ns) => (Arc::new(Int64Array::from(ns.clone())), false),
+            ColumnData::UInt8(ref ns) => (Arc::new(UInt8Array::from(ns.clone())), false),
+            ColumnData::UInt16(ref ns) => (Arc::new(UInt16Array::from(ns.clone())), false),
+            ColumnData::UInt32(ref ns) => (Arc::new(UInt32Array::from(ns.clone())), false),
+            ColumnData::UInt64(ref ns) => (Arc::new(UInt64Array::from(ns.clone())), false),
+            ColumnData::Float32(ref ns) => (Arc::new(Float32Array::from(ns.clone())), false),
+            ColumnData::Float64(ref ns) => (Arc::new(Float64Array::from(ns.clone())), false),
+            ColumnData::Text(ref ss) => (Arc::new(StringArray::from_iter_values(ss)), false),
+            ColumnData::Date(ref ds) => {
+                (Arc::new(Date32Array::from_iter_values(ds.iter().map(days_since_epoch))), false)
+            },
+            ColumnData::DateTime(ref ds) => {
+                (Arc::new(TimestampMicrosecondArray::from_iter_values(ds.iter().map(micros_since_epoch))), false)
+            },
+            ColumnData::Decimal(ref ss) => (Arc::new(StringArray::from_iter_values(ss)), false),
+            ColumnData::NullableInt8(ref ns) => (Arc::new(Int8Array::from(ns.clone())), true),
+            ColumnData::NullableInt16(ref ns) => (Arc::new(Int16Array::from(ns.clone())), true),
+            ColumnData::NullableInt32(ref ns) => (Arc::new(Int32Array::from(ns.clone())), true),
+            ColumnData::NullableInt64(ref ns) => (Arc::new(Int64Array::from(ns.clone())), true),
+            ColumnData::NullableUInt8(ref ns) => (Arc::new(UInt8Array::from(ns.clone())), true),
+            ColumnData::NullableUInt16(ref ns) => (Arc::new(UInt16Array::from(ns.clone())), true),
+            ColumnData::NullableUInt32(ref ns) => (Arc::new(UInt32Array::from(ns.clone())), true),
+            ColumnData::NullableUInt64(ref ns) => (Arc::new(UInt64Array::from(ns.clone())), true),
+            ColumnData::NullableFloat32(ref ns) => (Arc::new(Float32Array::from(ns.clone())), true),
+            ColumnData::NullableFloat64(ref ns) => (Arc::new(Float64Array::from(ns.clone())), true),
+            ColumnData::NullableText(ref ss) => {
+                (Arc::new(ss.iter().map(|s| s.as_ref()).collect::<StringArray>()), true)
+            },
+            ColumnData::NullableDate(ref ds) => {
+                (Arc::new(ds.iter().map(|d| d.as_ref().map(days_since_epoch)).collect::<Date32Array>()), true)
+            },
+            ColumnData::NullableDateTime(ref ds) => {
+                (Arc::new(ds.iter().map(|d| d.as_ref().map(micros_since_epoch)).collect::<TimestampMicrosecondArray>()), true)
+            },
+            ColumnData::NullableDecimal(ref ss) => {
+                (Arc::new(ss.iter().map(|s| s.as_ref()).collect::<StringArray>()), true)
+            },
+            ColumnData::ArrayInt8(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Int8Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayInt16(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Int16Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayInt32(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Int32Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayInt64(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Int64Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayUInt8(ref ns) => (Arc::new(ListArray::from_iter_primitive::<UInt8Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayUInt16(ref ns) => (Arc::new(ListArray::from_iter_primitive::<UInt16Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayUInt32(ref ns) => (Arc::new(ListArray::from_iter_primitive::<UInt32Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayUInt64(ref ns) => (Arc::new(ListArray::from_iter_primitive::<UInt64Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayFloat32(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Float32Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayFloat64(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Float64Type, _, _>(list_values(ns))), false),
+            ColumnData::ArrayText(ref ss) => {
+                let mut builder = ListBuilder::new(StringBuilder::new());
+                for members in ss {
+                    for member in members {
+                        builder.values().append_value(member);
+                    }
+                    builder.append(true);
+                }
+
+                (Arc::new(builder.finish()), false)
+            },
+            ColumnData::NullableArrayInt8(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Int8Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayInt16(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Int16Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayInt32(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Int32Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayInt64(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Int64Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayUInt8(ref ns) => (Arc::new(ListArray::from_iter_primitive::<UInt8Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayUInt16(ref ns) => (Arc::new(ListArray::from_iter_primitive::<UInt16Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayUInt32(ref ns) => (Arc::new(ListArray::from_iter_primitive::<UInt32Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayUInt64(ref ns) => (Arc::new(ListArray::from_iter_primitive::<UInt64Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayFloat32(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Float32Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayFloat64(ref ns) => (Arc::new(ListArray::from_iter_primitive::<Float64Type, _, _>(nullable_list_values(ns))), true),
+            ColumnData::NullableArrayText(ref ss) => {
+                let mut builder = ListBuilder::new(StringBuilder::new());
+                for members in ss {
+                    match members {
+                        Some(members) => {
+                            for member in members {
+                                builder.values().append_option(member.as_ref());
+                            }
+                            builder.append(true);
+                        },
+                        None => builder.append(false),
+                    }
+                }
+
+                (Arc::new(builder.finish()), true)
+            },
+        };
+
+        fields.push(Field::new(header, array.data_type().clone(), is_nullable));
+        arrays.push(array);
+    }
+
+    let batch = RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?;
+
+    Ok(batch)
+}
+
+/// Schema for a result with no rows, where the column types aren't known.
+pub fn empty_schema(headers: &[String]) -> Schema {
+    let fields: Vec<_> = headers.iter()
+        .map(|header| Field::new(header, DataType::Utf8, true))
+        .collect();
+
+    Schema::new(fields)
+}
+
+fn list_values<'a, T: Copy>(rows: &'a [Vec<T>]) -> impl Iterator<Item=Option<impl Iterator<Item=Option<T>> + 'a>> + 'a {
+    rows.iter().map(|members| Some(members.iter().map(|n| Some(*n))))
+}
+
+fn nullable_list_values<'a, T: Copy>(rows: &'a [Option<Vec<Option<T>>>]) -> impl Iterator<Item=Option<impl Iterator<Item=Option<T>> + 'a>> + 'a {
+    rows.iter().map(|members| members.as_ref().map(|members| members.iter().cloned()))
+}
+
+fn days_since_epoch(d: &NaiveDate) -> i32 {
+    d.num_days_from_ce() - UNIX_EPOCH_DAYS_FROM_CE
+}
+
+fn micros_since_epoch(dt: &NaiveDateTime) -> i64 {
+    dt.and_utc().timestamp_micros()
+}
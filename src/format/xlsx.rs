@@ -0,0 +1,208 @@
+//! Write DataFrames to an xlsx worksheet.
+//!
+//! Numbers are written as numbers, dates and datetimes as formatted excel
+//! dates, and everything else as text. Nulls are left as empty cells.
+//!
+//! Excel numbers are doubles, so 64 bit ints beyond 2^53, and decimals,
+//! may lose precision.
+
+use chrono::{NaiveDate, NaiveDateTime};
+use failure::Error;
+use itertools::join;
+use rust_xlsxwriter::{Format, Workbook, Worksheet};
+
+use crate::dataframe::{DataFrame, ColumnData};
+
+/// Excel's limit on the length of a worksheet name
+const MAX_SHEET_NAME_LEN: usize = 31;
+
+/// Excel's limit on the rows in a worksheet, less the header row
+pub const MAX_ROWS: u64 = 1_048_575;
+
+/// Creates a workbook with one worksheet, with the headers in the first row.
+pub fn new_workbook(name: &str, headers: &[String]) -> Result<Workbook, Error> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+
+    worksheet.set_name(sheet_name(name))?;
+
+    for (col_idx, header) in headers.iter().enumerate() {
+        worksheet.write_string(0, col_idx as u16, header)?;
+    }
+
+    Ok(workbook)
+}
+
+/// Writes the rows of the DataFrame, starting at `first_row`.
+pub fn write_df(
+    worksheet: &mut Worksheet,
+    df: &DataFrame,
+    first_row: u32,
+    array_delimiter: &str,
+    ) -> Result<(), Error>
+{
+    let date_format = Format::new().set_num_format("yyyy-mm-dd");
+    let datetime_format = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+
+    for (col_idx, col) in df.columns.iter().enumerate() {
+        let col_idx = col_idx as u16;
+        let mut cells = Cells { worksheet: &mut *worksheet, first_row, col_idx };
+
+        match col.column_data {
+            ColumnData::Int8(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n as f64)))?,
+            ColumnData::Int16(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n as f64)))?,
+            ColumnData::Int32(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n as f64)))?,
+            ColumnData::Int64(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n as f64)))?,
+            ColumnData::UInt8(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n as f64)))?,
+            ColumnData::UInt16(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n as f64)))?,
+            ColumnData::UInt32(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n as f64)))?,
+            ColumnData::UInt64(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n as f64)))?,
+            ColumnData::Float32(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n as f64)))?,
+            ColumnData::Float64(ref ns) => cells.numbers(ns.iter().map(|n| Some(*n)))?,
+            ColumnData::Text(ref ss) => cells.strings(ss.iter().map(Some))?,
+            ColumnData::Date(ref ds) => cells.dates(ds.iter().map(Some), &date_format)?,
+            ColumnData::DateTime(ref ds) => cells.datetimes(ds.iter().map(Some), &datetime_format)?,
+            ColumnData::Decimal(ref ss) => cells.decimals(ss.iter().map(Some))?,
+            ColumnData::NullableInt8(ref ns) => cells.numbers(ns.iter().map(|n| n.map(|n| n as f64)))?,
+            ColumnData::NullableInt16(ref ns) => cells.numbers(ns.iter().map(|n| n.map(|n| n as f64)))?,
+            ColumnData::NullableInt32(ref ns) => cells.numbers(ns.iter().map(|n| n.map(|n| n as f64)))?,
+            ColumnData::NullableInt64(ref ns) => cells.numbers(ns.iter().map(|n| n.map(|n| n as f64)))?,
+            ColumnData::NullableUInt8(ref ns) => cells.numbers(ns.iter().map(|n| n.map(|n| n as f64)))?,
+            ColumnData::NullableUInt16(ref ns) => cells.numbers(ns.iter().map(|n| n.map(|n| n as f64)))?,
+            ColumnData::NullableUInt32(ref ns) => cells.numbers(ns.iter().map(|n| n.map(|n| n as f64)))?,
+            ColumnData::NullableUInt64(ref ns) => cells.numbers(ns.iter().map(|n| n.map(|n| n as f64)))?,
+            ColumnData::NullableFloat32(ref ns) => cells.numbers(ns.iter().map(|n| n.map(|n| n as f64)))?,
+            ColumnData::NullableFloat64(ref ns) => cells.numbers(ns.iter().cloned())?,
+            ColumnData::NullableText(ref ss) => cells.strings(ss.iter().map(|s| s.as_ref()))?,
+            ColumnData::NullableDate(ref ds) => cells.dates(ds.iter().map(|d| d.as_ref()), &date_format)?,
+            ColumnData::NullableDateTime(ref ds) => cells.datetimes(ds.iter().map(|d| d.as_ref()), &datetime_format)?,
+            ColumnData::NullableDecimal(ref ss) => cells.decimals(ss.iter().map(|s| s.as_ref()))?,
+            ColumnData::ArrayInt8(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayInt16(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayInt32(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayInt64(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayUInt8(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayUInt16(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayUInt32(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayUInt64(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayFloat32(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayFloat64(ref ns) => cells.joined(ns, array_delimiter)?,
+            ColumnData::ArrayText(ref ss) => cells.joined(ss, array_delimiter)?,
+            ColumnData::NullableArrayInt8(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayInt16(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayInt32(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayInt64(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayUInt8(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayUInt16(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayUInt32(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayUInt64(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayFloat32(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayFloat64(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+            ColumnData::NullableArrayText(ref ns) => cells.nullable_joined(ns, array_delimiter)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes one column of cells; `None` leaves the cell empty.
+struct Cells<'a> {
+    worksheet: &'a mut Worksheet,
+    first_row: u32,
+    col_idx: u16,
+}
+
+impl<'a> Cells<'a> {
+    fn numbers<I: Iterator<Item=Option<f64>>>(&mut self, ns: I) -> Result<(), Error> {
+        for (row_idx, n) in (self.first_row..).zip(ns) {
+            if let Some(n) = n {
+                self.worksheet.write_number(row_idx, self.col_idx, n)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn strings<'s, I: Iterator<Item=Option<&'s String>>>(&mut self, ss: I) -> Result<(), Error> {
+        for (row_idx, s) in (self.first_row..).zip(ss) {
+            if let Some(s) = s {
+                self.worksheet.write_string(row_idx, self.col_idx, s)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decimals that can't be read as a number (e.g. out of range) are
+    /// written as text.
+    fn decimals<'s, I: Iterator<Item=Option<&'s String>>>(&mut self, ss: I) -> Result<(), Error> {
+        for (row_idx, s) in (self.first_row..).zip(ss) {
+            match s.map(|s| (s, s.parse::<f64>())) {
+                Some((_, Ok(n))) if n.is_finite() => {
+                    self.worksheet.write_number(row_idx, self.col_idx, n)?;
+                },
+                Some((s, _)) => {
+                    self.worksheet.write_string(row_idx, self.col_idx, s)?;
+                },
+                None => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn dates<'d, I: Iterator<Item=Option<&'d NaiveDate>>>(&mut self, ds: I, format: &Format) -> Result<(), Error> {
+        for (row_idx, d) in (self.first_row..).zip(ds) {
+            if let Some(d) = d {
+                self.worksheet.write_datetime_with_format(row_idx, self.col_idx, d, format)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn datetimes<'d, I: Iterator<Item=Option<&'d NaiveDateTime>>>(&mut self, ds: I, format: &Format) -> Result<(), Error> {
+        for (row_idx, d) in (self.first_row..).zip(ds) {
+            if let Some(d) = d {
+                self.worksheet.write_datetime_with_format(row_idx, self.col_idx, d, format)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Array members are joined by the delimiter, as in csv.
+    fn joined<T: ToString>(&mut self, rows: &[Vec<T>], array_delimiter: &str) -> Result<(), Error> {
+        for (row_idx, members) in (self.first_row..).zip(rows) {
+            let s = join(members.iter().map(|m| m.to_string()), array_delimiter);
+            self.worksheet.write_string(row_idx, self.col_idx, s)?;
+        }
+        Ok(())
+    }
+
+    /// As `joined`; null members are joined as empty strings.
+    fn nullable_joined<T: ToString>(&mut self, rows: &[Option<Vec<Option<T>>>], array_delimiter: &str) -> Result<(), Error> {
+        for (row_idx, members) in (self.first_row..).zip(rows) {
+            if let Some(members) = members {
+                let members = members.iter()
+                    .map(|m| m.as_ref().map(|m| m.to_string()).unwrap_or_default());
+                self.worksheet.write_string(row_idx, self.col_idx, join(members, array_delimiter))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Worksheet names are limited to 31 chars, can't contain `[]:*?/\`,
+/// can't start or end with an apostrophe, and can't be "History".
+fn sheet_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if "[]:*?/\\".contains(c) { '_' } else { c })
+        .take(MAX_SHEET_NAME_LEN)
+        .collect();
+
+    let name = name.trim_matches('\'');
+
+    if name.is_empty() {
+        "Sheet1".to_owned()
+    } else if name.eq_ignore_ascii_case("history") {
+        format!("{}_", name)
+    } else {
+        name.to_owned()
+    }
+}
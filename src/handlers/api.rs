@@ -1,30 +1,42 @@
 use actix_web::{
-    AsyncResponder,
     FutureResponse,
     HttpRequest,
     HttpResponse,
     Path,
 };
-use futures::future::{self, Future};
+use futures::future;
 use lazy_static::lazy_static;
 use log::*;
 use serde_qs as qs;
 use std::convert::TryInto;
 
 use crate::app::AppState;
-use crate::error::ServerError;
-use crate::format::{FormatType, format_records};
-use crate::query::Query;
-use super::api_shared::ApiQueryOpt;
+use crate::format::FormatType;
+use crate::query::{Query, LimitQuery};
+use crate::query_ir::FilterValueError;
+use super::api_shared::{
+    ApiQueryOpt,
+    CountSql,
+    CursorPage,
+    accept_format,
+    csv_dialect,
+    not_acceptable,
+    stream_records,
+    vary_on_accept,
+};
 
 /// Handles default aggregation when a format is not specified.
-/// Default format is CSV.
+/// The format is negotiated from the Accept header, and defaults to CSV.
 pub fn api_default_handler(
     (req, endpoint): (HttpRequest<AppState>, Path<String>)
     ) -> FutureResponse<HttpResponse>
 {
-    let endpoint_format = (endpoint.into_inner(), "csv".to_owned());
-    do_api(req, endpoint_format)
+    let format = match accept_format(&req) {
+        Some(f) => f,
+        None => return vary_on_accept(Box::new(future::result(Ok(not_acceptable())))),
+    };
+
+    vary_on_accept(do_api(req, (endpoint.into_inner(), format)))
 }
 
 /// Handles aggregation when a format is specified.
@@ -32,16 +44,7 @@ pub fn api_handler(
     (req, endpoint_format): (HttpRequest<AppState>, Path<(String, String)>)
     ) -> FutureResponse<HttpResponse>
 {
-    do_api(req, endpoint_format.into_inner())
-}
-
-/// Performs data aggregation.
-pub fn do_api(
-    req: HttpRequest<AppState>,
-    endpoint_format: (String, String),
-    ) -> FutureResponse<HttpResponse>
-{
-    let (endpoint, format) = endpoint_format;
+    let (endpoint, format) = endpoint_format.into_inner();
 
     let format = format.parse::<FormatType>();
     let format = match format {
@@ -55,6 +58,17 @@ pub fn do_api(
         },
     };
 
+    do_api(req, (endpoint, format))
+}
+
+/// Performs data aggregation.
+pub fn do_api(
+    req: HttpRequest<AppState>,
+    endpoint_format: (String, FormatType),
+    ) -> FutureResponse<HttpResponse>
+{
+    let (endpoint, format) = endpoint_format;
+
     info!("endpoint: {}, format: {:?}", endpoint, format);
 
     let query = req.query_string();
@@ -74,9 +88,20 @@ pub fn do_api(
     };
     info!("query opts:{:?}", api_query);
 
+    let csv_dialect = match csv_dialect(&req, &endpoint, api_query.csv.clone(), &format) {
+        Ok(d) => d,
+        Err(err) => {
+            return Box::new(
+                future::result(
+                    Ok(HttpResponse::NotFound().json(err.to_string()))
+                )
+            );
+        },
+    };
+
     // Turn ApiQueryOpt into Query
     let query: Result<Query, _> = api_query.try_into();
-    let query = match query {
+    let mut query = match query {
         Ok(q) => q,
         Err(err) => {
             return Box::new(
@@ -87,6 +112,27 @@ pub fn do_api(
         },
     };
 
+    // the workbook is only written once all rows are read, so too many rows
+    // must be caught before responding: a larger limit is rejected, and a
+    // missing one is capped
+    if let Some(max_rows) = format.max_rows() {
+        match query.limit {
+            Some(ref limit) if limit.n > max_rows => {
+                return Box::new(
+                    future::result(
+                        Ok(HttpResponse::BadRequest().json(
+                            format!("The limit can be at most {} rows for this format", max_rows)
+                        ))
+                    )
+                );
+            },
+            Some(_) => (),
+            None => {
+                query.limit = Some(LimitQuery { offset: None, n: max_rows });
+            },
+        }
+    }
+
     // Turn Query into QueryIr and headers (Vec<String>)
     let query_ir_headers = req
         .state()
@@ -96,40 +142,43 @@ pub fn do_api(
     let (query_ir, headers) = match query_ir_headers {
         Ok(x) => x,
         Err(err) => {
-            return Box::new(
-                future::result(
-                    Ok(HttpResponse::NotFound().json(err.to_string()))
-                )
-            );
+            // a filter value of the wrong type is a bad request, not a
+            // missing resource
+            let res = if err.downcast_ref::<FilterValueError>().is_some() {
+                HttpResponse::BadRequest().json(err.to_string())
+            } else {
+                HttpResponse::NotFound().json(err.to_string())
+            };
+
+            return Box::new(future::result(Ok(res)));
         },
     };
 
+    let count_sql = query.count.as_ref()
+        .map(|count| {
+            CountSql {
+                sql: req.state().backend.generate_count_sql(query_ir.clone(), count),
+                // offset links don't apply to keyset pagination
+                limit: if query.cursor.is_some() { None } else { query.limit.clone() },
+            }
+        });
+
+    let cursor_page = query_ir.cursor.as_ref()
+        .map(|cursor| {
+            CursorPage {
+                keys: cursor.keys.clone(),
+                n: query.limit.as_ref().map(|limit| limit.n).unwrap_or_default(),
+            }
+        });
+
     let sql = req.state()
         .backend
         .generate_sql(query_ir);
 
-    info!("Sql query: {}", sql);
+    info!("Sql query: {}", sql.sql);
+    info!("Sql params: {:?}", sql.params);
     info!("Headers: {:?}", headers);
 
     // Now pass request to backend
-    req.state()
-        .backend
-        .exec_sql(sql)
-        .and_then(move |df| {
-            match format_records(&headers, df, format) {
-                Ok(res) => Ok(HttpResponse::Ok().body(res)),
-                Err(err) => Ok(HttpResponse::NotFound().json(err.to_string())),
-            }
-        })
-        .map_err(move |e| {
-            error!("{}, {}", e.to_string(), e.as_fail());
-
-            if req.state().debug {
-                ServerError::Db { cause: e.to_string() }.into()
-            } else {
-                ServerError::Db { cause: "Internal Server Error 1010".to_owned() }.into()
-            }
-        })
-        .responder()
+    stream_records(req, sql, count_sql, cursor_page, endpoint, headers, format, csv_dialect)
 }
-
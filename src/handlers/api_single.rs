@@ -1,28 +1,42 @@
 use actix_web::{
-    AsyncResponder,
     FutureResponse,
     HttpRequest,
     HttpResponse,
     Path,
 };
-use futures::future::{self, Future};
+use futures::future;
 use indexmap::IndexMap;
+use lazy_static::lazy_static;
 use log::*;
+use serde_qs as qs;
 
 use crate::app::AppState;
-use crate::error::ServerError;
-use crate::format::{FormatType, format_records};
+use crate::format::FormatType;
 use crate::query::Query;
+use crate::query_ir::FilterValueError;
+use super::api_shared::{
+    CsvQueryOpt,
+    accept_format,
+    csv_dialect,
+    not_acceptable,
+    stream_records,
+    vary_on_accept,
+};
 
 /// Handles default aggregation when a format is not specified.
-/// Default format is CSV.
+/// The format is negotiated from the Accept header, and defaults to CSV.
 pub fn api_single_default_handler(
     (req, endpoint_id): (HttpRequest<AppState>, Path<(String, String)>)
     ) -> FutureResponse<HttpResponse>
 {
+    let format = match accept_format(&req) {
+        Some(f) => f,
+        None => return vary_on_accept(Box::new(future::result(Ok(not_acceptable())))),
+    };
+
     let endpoint_id = endpoint_id.into_inner();
-    let endpoint_id_format = (endpoint_id.0, endpoint_id.1, "csv".to_owned());
-    do_api_single(req, endpoint_id_format)
+    let endpoint_id_format = (endpoint_id.0, endpoint_id.1, format);
+    vary_on_accept(do_api_single(req, endpoint_id_format))
 }
 
 /// Handles aggregation when a format is specified.
@@ -30,20 +44,41 @@ pub fn api_single_handler(
     (req, endpoint_id_format): (HttpRequest<AppState>, Path<(String, String, String)>)
     ) -> FutureResponse<HttpResponse>
 {
-    do_api_single(req, endpoint_id_format.into_inner())
+    let (endpoint, id, format) = endpoint_id_format.into_inner();
+
+    let format = format.parse::<FormatType>();
+    let format = match format {
+        Ok(f) => f,
+        Err(err) => {
+            return Box::new(
+                future::result(
+                    Ok(HttpResponse::NotFound().json(err.to_string()))
+                )
+            );
+        },
+    };
+
+    do_api_single(req, (endpoint, id, format))
 }
 
 /// Performs data aggregation.
 pub fn do_api_single(
     req: HttpRequest<AppState>,
-    endpoint_id_format: (String, String, String),
+    endpoint_id_format: (String, String, FormatType),
     ) -> FutureResponse<HttpResponse>
 {
     let (endpoint, id, format) = endpoint_id_format;
 
-    let format = format.parse::<FormatType>();
-    let format = match format {
-        Ok(f) => f,
+    info!("endpoint: {}, format: {:?}", endpoint, format);
+
+    // only the csv options are taken from the query; there are no filters
+    let query = req.query_string();
+    lazy_static!{
+        static ref QS_NON_STRICT: qs::Config = qs::Config::new(5, false);
+    }
+    let csv_query_res = QS_NON_STRICT.deserialize_str::<CsvQueryOpt>(&query);
+    let csv_query = match csv_query_res {
+        Ok(q) => q,
         Err(err) => {
             return Box::new(
                 future::result(
@@ -53,7 +88,16 @@ pub fn do_api_single(
         },
     };
 
-    info!("endpoint: {}, format: {:?}", endpoint, format);
+    let csv_dialect = match csv_dialect(&req, &endpoint, csv_query, &format) {
+        Ok(d) => d,
+        Err(err) => {
+            return Box::new(
+                future::result(
+                    Ok(HttpResponse::NotFound().json(err.to_string()))
+                )
+            );
+        },
+    };
 
     // populate filter with just query where primary = id
     let primary = req.state()
@@ -102,8 +146,11 @@ pub fn do_api_single(
 
     let query = Query {
         filters: filters,
-        sort: None,
+        sort: vec![],
         limit: None,
+        fields: None,
+        count: None,
+        cursor: None,
     };
 
     // Turn Query into QueryIr and headers (Vec<String>)
@@ -115,11 +162,15 @@ pub fn do_api_single(
     let (query_ir, headers) = match query_ir_headers {
         Ok(x) => x,
         Err(err) => {
-            return Box::new(
-                future::result(
-                    Ok(HttpResponse::NotFound().json(err.to_string()))
-                )
-            );
+            // a filter value of the wrong type is a bad request, not a
+            // missing resource
+            let res = if err.downcast_ref::<FilterValueError>().is_some() {
+                HttpResponse::BadRequest().json(err.to_string())
+            } else {
+                HttpResponse::NotFound().json(err.to_string())
+            };
+
+            return Box::new(future::result(Ok(res)));
         },
     };
 
@@ -127,28 +178,10 @@ pub fn do_api_single(
         .backend
         .generate_sql(query_ir);
 
-    info!("Sql query: {}", sql);
+    info!("Sql query: {}", sql.sql);
+    info!("Sql params: {:?}", sql.params);
     info!("Headers: {:?}", headers);
 
     // Now pass request to backend
-    req.state()
-        .backend
-        .exec_sql(sql)
-        .and_then(move |df| {
-            match format_records(&headers, df, format) {
-                Ok(res) => Ok(HttpResponse::Ok().body(res)),
-                Err(err) => Ok(HttpResponse::NotFound().json(err.to_string())),
-            }
-        })
-        .map_err(move |e| {
-            error!("{}, {}", e.to_string(), e.as_fail());
-
-            if req.state().debug {
-                ServerError::Db { cause: e.to_string() }.into()
-            } else {
-                ServerError::Db { cause: "Internal Server Error 1010".to_owned() }.into()
-            }
-        })
-        .responder()
+    stream_records(req, sql, None, None, endpoint, headers, format, csv_dialect)
 }
-
@@ -5,6 +5,7 @@ use actix_web::{
     Result as ActixResult,
 };
 use failure::format_err;
+use itertools::join;
 use serde_derive::Serialize;
 
 use crate::app::AppState;
@@ -45,6 +46,11 @@ struct FilterMetadata {
     //annotations
     name: String,
     filter_type: FilterType,
+    /// operators in the filter value, e.g. `gt` in `gt.100`
+    operators: Vec<&'static str>,
+    /// How the filter treats nulls: a value (with any of the filter's value
+    /// operators) never matches a null, so nulls need `is.null`/`not.null`.
+    null_semantics: String,
 }
 
 fn endpoint_metadata(endpoint: &Endpoint) -> Metadata {
@@ -59,9 +65,29 @@ fn endpoint_metadata(endpoint: &Endpoint) -> Metadata {
 
     let filters = endpoint.interface.0.iter()
         .map(|(k, v)| {
+            let mut operators = match v.filter_type {
+                FilterType::Compare => vec!["eq", "neq", "lt", "lte", "gt", "gte", "between", "range"],
+                FilterType::ExactMatch => vec![],
+                FilterType::StringMatch if v.allow_regex => vec!["starts", "ends", "contains", "match"],
+                FilterType::StringMatch => vec!["starts", "ends", "contains"],
+                FilterType::InArray |
+                FilterType::OneOf => vec!["~"],
+            };
+
+            let values = if operators.is_empty() {
+                "Values".to_owned()
+            } else {
+                format!("Values, including with {},", join(&operators, ", "))
+            };
+            let null_semantics = format!("{} never match nulls; use is.null or not.null instead", values);
+
+            operators.extend(&["is.null", "not.null"]);
+
             FilterMetadata {
                 name: k.to_owned(),
                 filter_type: v.filter_type.clone(),
+                operators,
+                null_semantics,
             }
         })
         .collect();
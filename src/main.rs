@@ -3,13 +3,17 @@
 mod app;
 mod backend;
 mod clickhouse;
+mod compression;
 mod db_config;
 mod dataframe;
 mod error;
 mod format;
 mod handlers;
 mod middleware;
+mod mysql;
+mod postgres;
 mod schema;
+mod sqlite;
 mod query;
 mod query_ir;
 
@@ -22,6 +26,7 @@ use structopt::StructOpt;
 use tera::compile_templates;
 
 use crate::app::create_app;
+use crate::compression::CompressionConfig;
 use crate::schema::{Schema, SqlSelect};
 
 fn main() -> Result<(), Error> {
@@ -73,6 +78,26 @@ fn main() -> Result<(), Error> {
     };
     let with_api_key = api_key.is_some();
 
+    // delimiter for joining array members in csv output
+    let array_delimiter = std::env::var("BERYL_ARRAY_DELIMITER")
+        .ok()
+        .or(opt.array_delimiter)
+        .unwrap_or(",".into());
+
+    // compression of responses
+    let compression_min_size = match std::env::var("BERYL_COMPRESSION_MIN_SIZE").ok() {
+        Some(n) => Some(n.parse().map_err(|_| format_err!("BERYL_COMPRESSION_MIN_SIZE must be a number of bytes"))?),
+        None => opt.compression_min_size,
+    };
+    let compression_level = match std::env::var("BERYL_COMPRESSION_LEVEL").ok() {
+        Some(n) => Some(n.parse().map_err(|_| format_err!("BERYL_COMPRESSION_LEVEL must be a number"))?),
+        None => opt.compression_level,
+    };
+    let compression = CompressionConfig {
+        min_size: compression_min_size.unwrap_or(compression::DEFAULT_MIN_SIZE),
+        level: compression_level,
+    };
+
     // Database
     let db_url_full = std::env::var("BERYL_DATABASE_URL")
         .or(opt.database_url.ok_or(format_err!("")))
@@ -83,10 +108,14 @@ fn main() -> Result<(), Error> {
 
     // initialize system and server
 
-    let sys = actix::System::new("beryl");
+    let mut sys = actix::System::new("beryl");
+
+    // fail now on columns the db can't read, rather than on every query
+    sys.block_on(db.check_schema(&schema))
+        .map_err(|err| format_err!("Schema check against the database failed: {}", err))?;
 
     server::new(
-        move|| create_app(schema.clone(), db.clone(), sql_templates.clone(), api_key.clone(), debug)
+        move|| create_app(schema.clone(), db.clone(), sql_templates.clone(), api_key.clone(), array_delimiter.clone(), compression.clone(), debug)
     )
     .bind(&server_addr)
     .expect(&format!("cannot bind to {}", server_addr))
@@ -113,4 +142,13 @@ struct Opt {
 
     #[structopt(long="db-url")]
     database_url: Option<String>,
+
+    #[structopt(long="array-delimiter")]
+    array_delimiter: Option<String>,
+
+    #[structopt(long="compression-min-size")]
+    compression_min_size: Option<usize>,
+
+    #[structopt(long="compression-level")]
+    compression_level: Option<u32>,
 }
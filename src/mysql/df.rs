@@ -0,0 +1,213 @@
+//! Convert mysql Rows to DataFrame
+//!
+//! Signedness and nullability come from the column flags, so
+//! integer columns map to their exact `ColumnData` variant.
+
+use failure::{Error, bail, format_err};
+
+use mysql_async::{Column as MySqlColumn, Row};
+use mysql_async::consts::{ColumnFlags, ColumnType};
+use mysql_async::prelude::FromValue;
+use crate::dataframe::{DataFrame, Column, ColumnData};
+
+pub fn rows_to_df(mysql_columns: &[MySqlColumn], rows: Vec<Row>) -> Result<DataFrame, Error> {
+    let mut df = vec![];
+
+    for col in mysql_columns {
+        let flags = col.flags();
+        let is_unsigned = flags.contains(ColumnFlags::UNSIGNED_FLAG);
+        let is_nullable = !flags.contains(ColumnFlags::NOT_NULL_FLAG);
+
+        let column_data = match (col.column_type(), is_unsigned, is_nullable) {
+            (ColumnType::MYSQL_TYPE_TINY, false, false) => ColumnData::Int8(vec![]),
+            (ColumnType::MYSQL_TYPE_TINY, true, false) => ColumnData::UInt8(vec![]),
+            (ColumnType::MYSQL_TYPE_TINY, false, true) => ColumnData::NullableInt8(vec![]),
+            (ColumnType::MYSQL_TYPE_TINY, true, true) => ColumnData::NullableUInt8(vec![]),
+
+            (ColumnType::MYSQL_TYPE_SHORT, false, false) |
+            (ColumnType::MYSQL_TYPE_YEAR, false, false) => ColumnData::Int16(vec![]),
+            (ColumnType::MYSQL_TYPE_SHORT, true, false) |
+            (ColumnType::MYSQL_TYPE_YEAR, true, false) => ColumnData::UInt16(vec![]),
+            (ColumnType::MYSQL_TYPE_SHORT, false, true) |
+            (ColumnType::MYSQL_TYPE_YEAR, false, true) => ColumnData::NullableInt16(vec![]),
+            (ColumnType::MYSQL_TYPE_SHORT, true, true) |
+            (ColumnType::MYSQL_TYPE_YEAR, true, true) => ColumnData::NullableUInt16(vec![]),
+
+            (ColumnType::MYSQL_TYPE_INT24, false, false) |
+            (ColumnType::MYSQL_TYPE_LONG, false, false) => ColumnData::Int32(vec![]),
+            (ColumnType::MYSQL_TYPE_INT24, true, false) |
+            (ColumnType::MYSQL_TYPE_LONG, true, false) => ColumnData::UInt32(vec![]),
+            (ColumnType::MYSQL_TYPE_INT24, false, true) |
+            (ColumnType::MYSQL_TYPE_LONG, false, true) => ColumnData::NullableInt32(vec![]),
+            (ColumnType::MYSQL_TYPE_INT24, true, true) |
+            (ColumnType::MYSQL_TYPE_LONG, true, true) => ColumnData::NullableUInt32(vec![]),
+
+            (ColumnType::MYSQL_TYPE_LONGLONG, false, false) => ColumnData::Int64(vec![]),
+            (ColumnType::MYSQL_TYPE_LONGLONG, true, false) => ColumnData::UInt64(vec![]),
+            (ColumnType::MYSQL_TYPE_LONGLONG, false, true) => ColumnData::NullableInt64(vec![]),
+            (ColumnType::MYSQL_TYPE_LONGLONG, true, true) => ColumnData::NullableUInt64(vec![]),
+
+            (ColumnType::MYSQL_TYPE_FLOAT, _, false) => ColumnData::Float32(vec![]),
+            (ColumnType::MYSQL_TYPE_FLOAT, _, true) => ColumnData::NullableFloat32(vec![]),
+
+            (ColumnType::MYSQL_TYPE_DOUBLE, _, false) => ColumnData::Float64(vec![]),
+            (ColumnType::MYSQL_TYPE_DOUBLE, _, true) => ColumnData::NullableFloat64(vec![]),
+
+            (ColumnType::MYSQL_TYPE_DATE, _, false) |
+            (ColumnType::MYSQL_TYPE_NEWDATE, _, false) => ColumnData::Date(vec![]),
+            (ColumnType::MYSQL_TYPE_DATE, _, true) |
+            (ColumnType::MYSQL_TYPE_NEWDATE, _, true) => ColumnData::NullableDate(vec![]),
+
+            (ColumnType::MYSQL_TYPE_DATETIME, _, false) |
+            (ColumnType::MYSQL_TYPE_DATETIME2, _, false) |
+            (ColumnType::MYSQL_TYPE_TIMESTAMP, _, false) |
+            (ColumnType::MYSQL_TYPE_TIMESTAMP2, _, false) => ColumnData::DateTime(vec![]),
+            (ColumnType::MYSQL_TYPE_DATETIME, _, true) |
+            (ColumnType::MYSQL_TYPE_DATETIME2, _, true) |
+            (ColumnType::MYSQL_TYPE_TIMESTAMP, _, true) |
+            (ColumnType::MYSQL_TYPE_TIMESTAMP2, _, true) => ColumnData::NullableDateTime(vec![]),
+
+            // json (e.g. an array column) is passed through as text
+            (ColumnType::MYSQL_TYPE_VARCHAR, _, false) |
+            (ColumnType::MYSQL_TYPE_VAR_STRING, _, false) |
+            (ColumnType::MYSQL_TYPE_STRING, _, false) |
+            (ColumnType::MYSQL_TYPE_TINY_BLOB, _, false) |
+            (ColumnType::MYSQL_TYPE_MEDIUM_BLOB, _, false) |
+            (ColumnType::MYSQL_TYPE_LONG_BLOB, _, false) |
+            (ColumnType::MYSQL_TYPE_BLOB, _, false) |
+            (ColumnType::MYSQL_TYPE_ENUM, _, false) |
+            (ColumnType::MYSQL_TYPE_SET, _, false) |
+            (ColumnType::MYSQL_TYPE_JSON, _, false) => ColumnData::Text(vec![]),
+            (ColumnType::MYSQL_TYPE_VARCHAR, _, true) |
+            (ColumnType::MYSQL_TYPE_VAR_STRING, _, true) |
+            (ColumnType::MYSQL_TYPE_STRING, _, true) |
+            (ColumnType::MYSQL_TYPE_TINY_BLOB, _, true) |
+            (ColumnType::MYSQL_TYPE_MEDIUM_BLOB, _, true) |
+            (ColumnType::MYSQL_TYPE_LONG_BLOB, _, true) |
+            (ColumnType::MYSQL_TYPE_BLOB, _, true) |
+            (ColumnType::MYSQL_TYPE_ENUM, _, true) |
+            (ColumnType::MYSQL_TYPE_SET, _, true) |
+            (ColumnType::MYSQL_TYPE_JSON, _, true) => ColumnData::NullableText(vec![]),
+
+            // mysql sends decimals as text, so they're not lossy
+            (ColumnType::MYSQL_TYPE_DECIMAL, _, false) |
+            (ColumnType::MYSQL_TYPE_NEWDECIMAL, _, false) => ColumnData::Decimal(vec![]),
+            (ColumnType::MYSQL_TYPE_DECIMAL, _, true) |
+            (ColumnType::MYSQL_TYPE_NEWDECIMAL, _, true) => ColumnData::NullableDecimal(vec![]),
+
+            (t, _, _) => bail!("{:?} is not supported by beryl", t),
+        };
+
+        df.push(Column::new(
+            col.name_str().into_owned(),
+            column_data,
+        ));
+    }
+
+    for mut row in rows {
+        for col_idx in 0..row.len() {
+            let column_data = df
+                .get_mut(col_idx)
+                .expect("logic checked?")
+                .column_data();
+
+            match column_data {
+                ColumnData::UInt8(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::UInt16(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::UInt32(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::UInt64(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::Int8(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::Int16(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::Int32(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::Int64(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::Text(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::Float32(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::Float64(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableUInt8(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableUInt16(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableUInt32(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableUInt64(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableInt8(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableInt16(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableInt32(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableInt64(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableText(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableFloat32(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableFloat64(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::Date(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::DateTime(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableDate(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableDateTime(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::Decimal(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                ColumnData::NullableDecimal(col_data) => {
+                    col_data.push(take(&mut row, col_idx)?);
+                },
+                _ => unreachable!("array columns are not created from mysql rows"),
+            }
+        }
+    }
+
+    Ok(DataFrame::from_vec(df))
+}
+
+/// Takes the value out of the row, converting it to the `ColumnData` type.
+fn take<T: FromValue>(row: &mut Row, col_idx: usize) -> Result<T, Error> {
+    let val = row.take_opt(col_idx)
+        .ok_or_else(|| format_err!("No value in mysql row for column {}", col_idx))?;
+
+    Ok(val?)
+}
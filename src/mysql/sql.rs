@@ -0,0 +1,296 @@
+use itertools::join;
+
+use crate::backend::SqlQuery;
+use crate::query::{CountQuery, NullsOrder};
+use crate::query_ir::{QueryIr, Constraint, CursorIr, FilterExpr, FilterIr, FilterValue, StringMatchKind};
+
+pub fn mysql_sql(query_ir: QueryIr) -> SqlQuery {
+    let project_cols_sql = join(query_ir.projection.iter().map(|col| quote_ident(col)), ", ");
+
+    let table = table_sql(&query_ir.table);
+
+    let mut params = vec![];
+
+    let filter_sql = filter_sql(&query_ir.filters, query_ir.cursor.as_ref(), &mut params);
+
+    let sort_sql = if !query_ir.sort.is_empty() {
+        // mysql has no `nulls first` or `nulls last`, so they're emulated by
+        // sorting on whether the column is null first
+        let sorts = query_ir.sort.iter()
+            .map(|srt| {
+                let column = quote_ident(&srt.column);
+                let sort = format!("{} {}", column, srt.direction.sql_string());
+
+                match srt.nulls {
+                    Some(NullsOrder::First) => format!("({}) is null desc, {}", column, sort),
+                    Some(NullsOrder::Last) => format!("({}) is null asc, {}", column, sort),
+                    None => sort,
+                }
+            });
+
+        format!("order by {}", join(sorts, ", "))
+    } else {
+        "".into()
+    };
+
+    let limit_sql = {
+        if let Some(lmt) = query_ir.limit {
+            if let Some(offset) = lmt.offset {
+                format!("limit {}, {}", offset, lmt.n)
+            } else {
+                format!("limit {}", lmt.n)
+            }
+        } else {
+            "".to_string()
+        }
+    };
+
+    let sql = format!("select {} from {} {} {} {}",
+        project_cols_sql,
+        table,
+        filter_sql,
+        sort_sql,
+        limit_sql,
+    );
+
+    SqlQuery {
+        sql,
+        params,
+    }
+}
+
+/// Counts the rows matching the filters, ignoring sort and limit.
+///
+/// An estimated count of a whole table is read from `information_schema`,
+/// which for innodb is only approximate, falling back to an exact count if
+/// the table has no statistics. With filters, or for a sql template, the
+/// count is exact.
+pub fn mysql_count_sql(query_ir: QueryIr, count: &CountQuery) -> SqlQuery {
+    let table = table_sql(&query_ir.table);
+
+    let mut params = vec![];
+
+    let filter_sql = filter_sql(&query_ir.filters, None, &mut params);
+
+    let count_sql = format!("select count(*) from {} {}", table, filter_sql);
+
+    let is_template = query_ir.table.starts_with('(');
+
+    let sql = match count {
+        CountQuery::Estimated if query_ir.filters.is_empty() && !is_template => {
+            // the table may be qualified by its database
+            let schema_sql = match query_ir.table.rfind('.') {
+                Some(idx) => {
+                    params.push(FilterValue::Text(query_ir.table[..idx].to_owned()));
+                    params.push(FilterValue::Text(query_ir.table[idx + 1..].to_owned()));
+                    "table_schema = ? and table_name = ?"
+                },
+                None => {
+                    params.push(FilterValue::Text(query_ir.table.clone()));
+                    "table_schema = database() and table_name = ?"
+                },
+            };
+
+            format!("select cast(coalesce((select table_rows from information_schema.tables where {} and table_rows > 0), ({})) as unsigned)",
+                schema_sql,
+                count_sql,
+            )
+        },
+        _ => count_sql,
+    };
+
+    SqlQuery {
+        sql,
+        params,
+    }
+}
+
+/// A rendered sql template is a subquery, which mysql requires to be aliased
+fn table_sql(table: &str) -> String {
+    if table.starts_with('(') {
+        format!("{} as beryl_template", table)
+    } else {
+        quote_ident(table)
+    }
+}
+
+fn filter_sql(filter_exprs: &[FilterExpr], cursor: Option<&CursorIr>, params: &mut Vec<FilterValue>) -> String {
+    let mut filters = vec![];
+
+    for expr in filter_exprs {
+        filters.push(expr.sql_string(&mut |f| constraint_sql(f, params)));
+    }
+
+    // keyset pagination: the rows after the cursor, in the sort order
+    if let Some(cursor) = cursor {
+        let cursor_sql = cursor.sql_string(|col| quote_ident(col), |value| {
+            params.push(value.clone());
+            "?".to_owned()
+        });
+
+        filters.extend(cursor_sql);
+    }
+
+    if !filters.is_empty() {
+        format!("where {}", join(filters, " and "))
+    } else {
+        "".into()
+    }
+}
+
+fn constraint_sql(f: &FilterIr, params: &mut Vec<FilterValue>) -> String {
+    let column = quote_ident(&f.column);
+
+    match &f.constraint {
+        Constraint::CompareList ( ref compare_list )=> {
+            let comparisons = compare_list.iter()
+                .map(|compare| {
+                    params.push(compare.n.clone());
+
+                    format!("{} {} ?",
+                        column,
+                        compare.comparison.sql_string(),
+                    )
+                });
+
+            join(comparisons, " and ")
+        },
+        Constraint::ExactMatch { ref pattern } => {
+            params.push(pattern.clone());
+
+            format!("{} = ?", column)
+        },
+        Constraint::StringMatch { ref kind, ref substring } => {
+            // LIKE escapes with `\` by default
+            match kind {
+                StringMatchKind::Contains |
+                StringMatchKind::StartsWith |
+                StringMatchKind::EndsWith => {
+                    params.push(FilterValue::Text(kind.like_pattern(&substring.to_lowercase())));
+
+                    format!("LOWER({}) LIKE ?", column)
+                },
+                StringMatchKind::ContainsCaseSensitive => {
+                    params.push(FilterValue::Text(kind.like_pattern(substring)));
+
+                    format!("CAST({} AS BINARY) LIKE ?", column)
+                },
+            }
+        },
+        Constraint::RegexMatch { ref regex } => {
+            params.push(FilterValue::Text(regex.clone()));
+
+            // case sensitivity follows the column's collation
+            format!("{} REGEXP ?", column)
+        },
+        Constraint::InArray { ref in_members, ref not_in_members } => {
+            // mysql has no array type, so arrays are expected
+            // to be stored as json arrays.
+            let json_array_sql = |n: usize| {
+                format!("JSON_ARRAY({})", join(std::iter::repeat("?").take(n), ", "))
+            };
+
+            let mut res = vec![];
+            if !in_members.is_empty() {
+                params.extend(in_members.iter().cloned());
+
+                res.push(format!("JSON_CONTAINS({}, {})",
+                    column,
+                    json_array_sql(in_members.len()),
+                ));
+            };
+            // one check per member instead of JSON_OVERLAPS,
+            // which isn't available in older mysql or mariadb
+            for m in not_in_members {
+                params.push(m.clone());
+
+                res.push(format!("NOT JSON_CONTAINS({}, {})",
+                    column,
+                    json_array_sql(1),
+                ));
+            }
+
+            join(res, " AND ")
+        },
+        Constraint::OneOf { ref in_members, ref not_in_members } => {
+            let list_sql = |n: usize| join(std::iter::repeat("?").take(n), ", ");
+
+            let mut res = vec![];
+            if !in_members.is_empty() {
+                params.extend(in_members.iter().cloned());
+
+                res.push(format!("{} IN ({})", column, list_sql(in_members.len())));
+            }
+            if !not_in_members.is_empty() {
+                params.extend(not_in_members.iter().cloned());
+
+                res.push(format!("{} NOT IN ({})", column, list_sql(not_in_members.len())));
+            }
+
+            join(res, " AND ")
+        },
+        Constraint::IsNull => format!("{} IS NULL", column),
+        Constraint::IsNotNull => format!("{} IS NOT NULL", column),
+    }
+}
+
+/// Quotes each part of a (possibly database-qualified) identifier in backticks.
+/// Anything else is an expression from the schema (e.g. `concat(a, b)`),
+/// which is left as written.
+fn quote_ident(ident: &str) -> String {
+    let is_plain = ident.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+
+    if !is_plain {
+        return ident.to_owned();
+    }
+
+    let parts = ident.split('.')
+        .map(|part| format!("`{}`", part));
+
+    join(parts, ".")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query_ir::test::{HOSTILE_VALUES, assert_only_bound, query_ir_with_every_constraint};
+
+    #[test]
+    fn hostile_values_are_bound() {
+        let benign = mysql_sql(query_ir_with_every_constraint("a"));
+
+        for value in HOSTILE_VALUES {
+            assert_only_bound(value, mysql_sql(query_ir_with_every_constraint(value)), &benign);
+        }
+    }
+
+    #[test]
+    fn hostile_values_are_bound_in_count() {
+        let benign = mysql_count_sql(query_ir_with_every_constraint("a"), &CountQuery::Estimated);
+
+        for value in HOSTILE_VALUES {
+            assert_only_bound(value, mysql_count_sql(query_ir_with_every_constraint(value), &CountQuery::Estimated), &benign);
+        }
+    }
+
+    #[test]
+    fn only_plain_identifiers_are_quoted() {
+        let mut query_ir = query_ir_with_every_constraint("a");
+        query_ir.table = "db.t".to_owned();
+        query_ir.projection = vec!["col".to_owned(), "concat(a, b)".to_owned(), "a + b".to_owned()];
+        query_ir.filters = vec![];
+        query_ir.cursor = None;
+
+        let SqlQuery { sql, .. } = mysql_sql(query_ir);
+
+        assert!(sql.starts_with("select `col`, concat(a, b), a + b from `db`.`t`"), "{}", sql);
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        let SqlQuery { params, .. } = mysql_sql(query_ir_with_every_constraint("100%_\\"));
+
+        assert!(params.contains(&FilterValue::Text("%100\\%\\_\\\\%".to_owned())));
+    }
+}
@@ -0,0 +1,126 @@
+//! Convert postgres Rows to DataFrame
+//!
+//! The row description from postgres doesn't say whether a column
+//! is nullable, so every column is read into a nullable `ColumnData`.
+//! Postgres array members can also be null.
+
+use chrono::{DateTime, Utc};
+use failure::{Error, bail};
+
+use tokio_postgres::{Column as PgColumn, Row};
+use tokio_postgres::types::Type;
+use crate::dataframe::{DataFrame, Column, ColumnData};
+
+pub fn rows_to_df(pg_columns: &[PgColumn], rows: Vec<Row>) -> Result<DataFrame, Error> {
+    let mut df = vec![];
+
+    for col in pg_columns {
+        let column_data = match *col.type_() {
+            // there's no bool ColumnData; follow clickhouse and use UInt8
+            Type::BOOL => ColumnData::NullableUInt8(vec![]),
+            Type::CHAR => ColumnData::NullableInt8(vec![]),
+            Type::INT2 => ColumnData::NullableInt16(vec![]),
+            Type::INT4 => ColumnData::NullableInt32(vec![]),
+            Type::INT8 => ColumnData::NullableInt64(vec![]),
+            Type::OID => ColumnData::NullableUInt32(vec![]),
+            Type::FLOAT4 => ColumnData::NullableFloat32(vec![]),
+            Type::FLOAT8 => ColumnData::NullableFloat64(vec![]),
+            Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => ColumnData::NullableText(vec![]),
+            Type::DATE => ColumnData::NullableDate(vec![]),
+            // timestamptz is converted to utc
+            Type::TIMESTAMP | Type::TIMESTAMPTZ => ColumnData::NullableDateTime(vec![]),
+            Type::CHAR_ARRAY => ColumnData::NullableArrayInt8(vec![]),
+            Type::INT2_ARRAY => ColumnData::NullableArrayInt16(vec![]),
+            Type::INT4_ARRAY => ColumnData::NullableArrayInt32(vec![]),
+            Type::INT8_ARRAY => ColumnData::NullableArrayInt64(vec![]),
+            Type::OID_ARRAY => ColumnData::NullableArrayUInt32(vec![]),
+            Type::FLOAT4_ARRAY => ColumnData::NullableArrayFloat32(vec![]),
+            Type::FLOAT8_ARRAY => ColumnData::NullableArrayFloat64(vec![]),
+            Type::TEXT_ARRAY | Type::VARCHAR_ARRAY | Type::BPCHAR_ARRAY | Type::NAME_ARRAY => ColumnData::NullableArrayText(vec![]),
+            ref t => bail!("{} is not supported by beryl", t),
+        };
+
+        df.push(Column::new(
+            col.name().to_owned(),
+            column_data,
+        ));
+    }
+
+    for row in rows {
+        for col_idx in 0..row.len() {
+            let column_data = df
+                .get_mut(col_idx)
+                .expect("logic checked?")
+                .column_data();
+
+            match column_data {
+                ColumnData::NullableUInt8(col_data) => {
+                    let val: Option<bool> = row.try_get(col_idx)?;
+                    col_data.push(val.map(|b| b as u8));
+                },
+                ColumnData::NullableInt8(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableInt16(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableInt32(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableInt64(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableUInt32(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableFloat32(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableFloat64(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableText(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableDate(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableDateTime(col_data) => {
+                    if *pg_columns[col_idx].type_() == Type::TIMESTAMPTZ {
+                        let val: Option<DateTime<Utc>> = row.try_get(col_idx)?;
+                        col_data.push(val.map(|dt| dt.naive_utc()));
+                    } else {
+                        col_data.push(row.try_get(col_idx)?);
+                    }
+                },
+                ColumnData::NullableArrayInt8(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableArrayInt16(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableArrayInt32(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableArrayInt64(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableArrayUInt32(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableArrayFloat32(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableArrayFloat64(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                ColumnData::NullableArrayText(col_data) => {
+                    col_data.push(row.try_get(col_idx)?);
+                },
+                _ => unreachable!("only nullable and array columns are created from postgres rows"),
+            }
+        }
+    }
+
+    Ok(DataFrame::from_vec(df))
+}
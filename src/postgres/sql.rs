@@ -0,0 +1,285 @@
+use itertools::join;
+
+use crate::backend::SqlQuery;
+use crate::query::CountQuery;
+use crate::query_ir::{QueryIr, Constraint, CursorIr, FilterExpr, FilterIr, FilterValue, StringMatchKind};
+
+pub fn postgres_sql(query_ir: QueryIr) -> SqlQuery {
+    let project_cols_sql = join(query_ir.projection, ", ");
+
+    let table = table_sql(&query_ir.table);
+
+    let mut params = vec![];
+
+    let filter_sql = filter_sql(&query_ir.filters, query_ir.cursor.as_ref(), &mut params);
+
+    let sort_sql = if !query_ir.sort.is_empty() {
+        let sorts = query_ir.sort.iter()
+            .map(|srt| {
+                if let Some(ref nulls) = srt.nulls {
+                    format!("{} {} {}",
+                        srt.column,
+                        srt.direction.sql_string(),
+                        nulls.sql_string(),
+                    )
+                } else {
+                    format!("{} {}",
+                        srt.column,
+                        srt.direction.sql_string(),
+                    )
+                }
+            });
+
+        format!("order by {}", join(sorts, ", "))
+    } else {
+        "".into()
+    };
+
+    let limit_sql = {
+        if let Some(lmt) = query_ir.limit {
+            if let Some(offset) = lmt.offset {
+                format!("limit {} offset {}", lmt.n, offset)
+            } else {
+                format!("limit {}", lmt.n)
+            }
+        } else {
+            "".to_string()
+        }
+    };
+
+    let sql = format!("select {} from {} {} {} {}",
+        project_cols_sql,
+        table,
+        filter_sql,
+        sort_sql,
+        limit_sql,
+    );
+
+    SqlQuery {
+        sql,
+        params,
+    }
+}
+
+/// Counts the rows matching the filters, ignoring sort and limit.
+///
+/// An estimated count of a whole table is read from the planner's
+/// statistics, falling back to an exact count if the table has never been
+/// analyzed. With filters, or for a sql template, the count is exact.
+pub fn postgres_count_sql(query_ir: QueryIr, count: &CountQuery) -> SqlQuery {
+    let table = table_sql(&query_ir.table);
+
+    let mut params = vec![];
+
+    let filter_sql = filter_sql(&query_ir.filters, None, &mut params);
+
+    let count_sql = format!("select count(*) from {} {}", table, filter_sql);
+
+    let is_template = query_ir.table.starts_with('(');
+
+    let sql = match count {
+        CountQuery::Estimated if query_ir.filters.is_empty() && !is_template => {
+            let table_name = placeholder(&mut params, &FilterValue::Text(query_ir.table.clone()));
+
+            format!("select coalesce((select reltuples::int8 from pg_class where oid = to_regclass({}) and reltuples > 0), ({}))",
+                table_name,
+                count_sql,
+            )
+        },
+        _ => count_sql,
+    };
+
+    SqlQuery {
+        sql,
+        params,
+    }
+}
+
+/// A rendered sql template is a subquery, which postgres requires to be aliased
+fn table_sql(table: &str) -> String {
+    if table.starts_with('(') {
+        format!("{} as beryl_template", table)
+    } else {
+        table.to_owned()
+    }
+}
+
+fn filter_sql(filter_exprs: &[FilterExpr], cursor: Option<&CursorIr>, params: &mut Vec<FilterValue>) -> String {
+    let mut filters = vec![];
+
+    for expr in filter_exprs {
+        filters.push(expr.sql_string(&mut |f| constraint_sql(f, params)));
+    }
+
+    // keyset pagination: the rows after the cursor, in the sort order
+    if let Some(cursor) = cursor {
+        let cursor_sql = cursor.sql_string(|col| col.to_owned(), |value| placeholder(params, value));
+
+        filters.extend(cursor_sql);
+    }
+
+    if !filters.is_empty() {
+        format!("where {}", join(filters, " and "))
+    } else {
+        "".into()
+    }
+}
+
+fn constraint_sql(f: &FilterIr, params: &mut Vec<FilterValue>) -> String {
+    match &f.constraint {
+        Constraint::CompareList ( ref compare_list )=> {
+            let comparisons = compare_list.iter()
+                .map(|compare| {
+                    format!("{} {} {}",
+                        f.column,
+                        compare.comparison.sql_string(),
+                        placeholder(params, &compare.n),
+                    )
+                });
+
+            join(comparisons, " and ")
+        },
+        Constraint::ExactMatch { ref pattern } => {
+            format!("{} = {}",
+                f.column,
+                placeholder(params, pattern),
+            )
+        },
+        Constraint::StringMatch { ref kind, ref substring } => {
+            // LIKE escapes with `\` by default
+            match kind {
+                StringMatchKind::Contains |
+                StringMatchKind::StartsWith |
+                StringMatchKind::EndsWith => {
+                    format!("{} ILIKE {}",
+                        f.column,
+                        placeholder(params, &FilterValue::Text(kind.like_pattern(substring))),
+                    )
+                },
+                StringMatchKind::ContainsCaseSensitive => {
+                    format!("{} LIKE {}",
+                        f.column,
+                        placeholder(params, &FilterValue::Text(kind.like_pattern(substring))),
+                    )
+                },
+            }
+        },
+        Constraint::RegexMatch { ref regex } => {
+            format!("{} ~ {}",
+                f.column,
+                placeholder(params, &FilterValue::Text(regex.clone())),
+            )
+        },
+        Constraint::InArray { ref in_members, ref not_in_members } => {
+            // members are compared as text so that their type
+            // doesn't have to match the array's element type exactly
+            let mut array_sql = |members: &[FilterValue]| {
+                let ms: Vec<_> = members
+                    .iter()
+                    .map(|m| placeholder(params, &FilterValue::Text(m.to_string())))
+                    .collect();
+
+                format!("ARRAY[{}]", join(ms, ", "))
+            };
+
+            let mut res = String::new();
+            if !in_members.is_empty() {
+                // array contains all members
+                res.push_str(&format!("{}::text[] @> {}",
+                    f.column,
+                    array_sql(in_members),
+                ));
+            };
+            if !not_in_members.is_empty() {
+                if !in_members.is_empty() {
+                    res.push_str(" AND ");
+                }
+
+                // array overlaps with none of the members
+                res.push_str(&format!("NOT {}::text[] && {}",
+                    f.column,
+                    array_sql(not_in_members),
+                ));
+            };
+
+            res
+        },
+        Constraint::OneOf { ref in_members, ref not_in_members } => {
+            let mut list_sql = |members: &[FilterValue]| {
+                let ms: Vec<_> = members
+                    .iter()
+                    .map(|m| placeholder(params, m))
+                    .collect();
+
+                join(ms, ", ")
+            };
+
+            let mut res = vec![];
+            if !in_members.is_empty() {
+                res.push(format!("{} IN ({})", f.column, list_sql(in_members)));
+            }
+            if !not_in_members.is_empty() {
+                res.push(format!("{} NOT IN ({})", f.column, list_sql(not_in_members)));
+            }
+
+            join(res, " AND ")
+        },
+        Constraint::IsNull => format!("{} IS NULL", f.column),
+        Constraint::IsNotNull => format!("{} IS NOT NULL", f.column),
+    }
+}
+
+/// Adds the value to the params, and returns its placeholder.
+///
+/// Placeholders are cast explicitly, otherwise postgres infers the param
+/// type from the column and the bound value's type may not match it.
+/// A UInt has no matching postgres type, so it's bound as text (see
+/// `Postgres::exec_sql`) and cast to numeric; dates and decimals are
+/// bound as text and cast the same way.
+fn placeholder(params: &mut Vec<FilterValue>, value: &FilterValue) -> String {
+    params.push(value.clone());
+
+    let cast = match value {
+        FilterValue::Text(_) => "text",
+        FilterValue::Int(_) => "int8",
+        FilterValue::UInt(_) => "text::numeric",
+        FilterValue::Float(_) => "float8",
+        FilterValue::Date(_) => "text::date",
+        FilterValue::DateTime(_) => "text::timestamp",
+        FilterValue::Bool(_) => "bool",
+        FilterValue::Decimal(_) => "text::numeric",
+    };
+
+    format!("${}::{}", params.len(), cast)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query_ir::test::{HOSTILE_VALUES, assert_only_bound, query_ir_with_every_constraint};
+
+    #[test]
+    fn hostile_values_are_bound() {
+        let benign = postgres_sql(query_ir_with_every_constraint("a"));
+
+        for value in HOSTILE_VALUES {
+            assert_only_bound(value, postgres_sql(query_ir_with_every_constraint(value)), &benign);
+        }
+    }
+
+    #[test]
+    fn hostile_values_are_bound_in_count() {
+        let benign = postgres_count_sql(query_ir_with_every_constraint("a"), &CountQuery::Estimated);
+
+        for value in HOSTILE_VALUES {
+            assert_only_bound(value, postgres_count_sql(query_ir_with_every_constraint(value), &CountQuery::Estimated), &benign);
+        }
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        let SqlQuery { params, .. } = postgres_sql(query_ir_with_every_constraint("100%_\\"));
+
+        assert!(params.contains(&FilterValue::Text("%100\\%\\_\\\\%".to_owned())));
+    }
+}
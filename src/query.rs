@@ -10,20 +10,116 @@
 //! directly produce a sql statement.
 //!
 
-use failure::{Error, bail};
+use failure::{Error, bail, format_err};
 use indexmap::IndexMap;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
 use std::str::FromStr;
 
 pub struct Query {
     pub filters: FiltersQuery,
-    pub sort: Option<SortQuery>,
+    pub sort: Vec<SortQuery>,
     pub limit: Option<LimitQuery>,
+    pub fields: Option<FieldsQuery>,
+    pub count: Option<CountQuery>,
+    pub cursor: Option<CursorQuery>,
 }
 
 pub type FiltersQuery = IndexMap<String, FilterQuery>;
 
 pub type FilterQuery = String;
 
+/// Names of the interface params to output, in order.
+#[derive(Debug, Clone)]
+pub struct FieldsQuery(pub Vec<String>);
+
+impl FromStr for FieldsQuery {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split(",")
+            .map(|name| name.trim().to_owned())
+            .collect();
+
+        if fields.iter().any(|name| name.is_empty()) {
+            bail!("Could not parse a fields query");
+        }
+
+        Ok(FieldsQuery(fields))
+    }
+}
+
+/// Whether to count all the rows matching the filters, regardless of the
+/// limit. An estimate may be much cheaper for large tables.
+#[derive(Debug, Clone)]
+pub enum CountQuery {
+    Exact,
+    Estimated,
+}
+
+impl FromStr for CountQuery {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "exact" => CountQuery::Exact,
+            "estimated" => CountQuery::Estimated,
+            _ => bail!("Could not parse count; must be exact or estimated"),
+        })
+    }
+}
+
+/// A cursor for keyset pagination. It's opaque to clients: the sort key
+/// names and the last row's values, as base64 encoded json. The cursor `*`
+/// requests the first page.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CursorQuery {
+    Start,
+    After(CursorValues),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CursorValues {
+    pub keys: Vec<String>,
+    /// `None` for a null
+    pub values: Vec<Option<String>>,
+}
+
+impl FromStr for CursorQuery {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(CursorQuery::Start);
+        }
+
+        let invalid = || format_err!("Could not parse cursor");
+
+        let json = base64::decode_config(s, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| invalid())?;
+        let cursor: CursorValues = serde_json::from_slice(&json)
+            .map_err(|_| invalid())?;
+
+        if cursor.keys.is_empty() || cursor.keys.len() != cursor.values.len() {
+            return Err(invalid());
+        }
+
+        Ok(CursorQuery::After(cursor))
+    }
+}
+
+impl fmt::Display for CursorQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CursorQuery::Start => write!(f, "*"),
+            CursorQuery::After(cursor) => {
+                let json = serde_json::to_vec(cursor).map_err(|_| fmt::Error)?;
+                write!(f, "{}", base64::encode_config(&json, base64::URL_SAFE_NO_PAD))
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LimitQuery {
     pub offset: Option<u64>,
@@ -56,6 +152,7 @@ impl FromStr for LimitQuery {
 pub struct SortQuery {
     pub direction: SortDirection,
     pub name: String,
+    pub nulls: Option<NullsOrder>,
 }
 
 impl FromStr for SortQuery {
@@ -69,6 +166,17 @@ impl FromStr for SortQuery {
                 Ok(SortQuery {
                     direction,
                     name,
+                    nulls: None,
+                })
+            },
+            [name, direction, nulls] => {
+                let name = name.to_string();
+                let direction = direction.parse::<SortDirection>()?;
+                let nulls = nulls.parse::<NullsOrder>()?;
+                Ok(SortQuery {
+                    direction,
+                    name,
+                    nulls: Some(nulls),
                 })
             },
             _ => bail!("Could not parse a sort query"),
@@ -77,7 +185,7 @@ impl FromStr for SortQuery {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SortDirection {
     Asc,
     Desc,
@@ -104,3 +212,30 @@ impl FromStr for SortDirection {
     }
 }
 
+/// Where nulls go in a sort. When not given, it's up to the database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NullsOrder {
+    First,
+    Last,
+}
+
+impl NullsOrder {
+    pub fn sql_string(&self) -> String {
+        match *self {
+            NullsOrder::First => "nulls first".to_owned(),
+            NullsOrder::Last => "nulls last".to_owned(),
+        }
+    }
+}
+
+impl FromStr for NullsOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "nullsfirst" => NullsOrder::First,
+            "nullslast" => NullsOrder::Last,
+            _ => bail!("Could not parse sort nulls order"),
+        })
+    }
+}
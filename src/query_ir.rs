@@ -7,17 +7,25 @@
 //! In some cases, the only difference is mapping a "name" in the interface
 //! to a column
 
+mod filter_expr;
 mod filter_ir;
 
+use itertools::join;
+
 use crate::query::{
     LimitQuery,
+    NullsOrder,
     SortDirection
 };
 
+pub use self::filter_expr::FilterExpr;
 pub use self::filter_ir::{
     FilterIr,
+    FilterValue,
+    FilterValueError,
     Constraint,
     Comparison,
+    StringMatchKind,
 };
 
 #[derive(Debug, Clone)]
@@ -26,9 +34,11 @@ pub struct QueryIr {
     // headers for formatting are separate from projection cols
     pub projection: Vec<String>,
 
-    pub filters: Vec<FilterIr>,
-    pub sort: Option<SortIr>,
+    /// and-ed together
+    pub filters: Vec<FilterExpr>,
+    pub sort: Vec<SortIr>,
     pub limit: Option<LimitQuery>,
+    pub cursor: Option<CursorIr>,
 //    dimension joins?
 }
 
@@ -36,4 +46,200 @@ pub struct QueryIr {
 pub struct SortIr {
     pub direction: SortDirection,
     pub column: String,
+    pub nulls: Option<NullsOrder>,
+}
+
+/// Keyset pagination. The sort keys always end with the primary key, so
+/// that the order is total, and are also selected after the projection,
+/// so that the next cursor can be read from the last row.
+#[derive(Debug, Clone)]
+pub struct CursorIr {
+    /// interface param names of the sort keys
+    pub keys: Vec<String>,
+    pub columns: Vec<String>,
+    /// all sort keys have the same direction and nulls order, so that
+    /// "after" means the same for each key
+    pub direction: SortDirection,
+    pub nulls: NullsOrder,
+    /// sort key values of the last row of the previous page (`None` for a
+    /// null); `None` for the first page
+    pub after: Option<Vec<Option<FilterValue>>>,
+}
+
+impl CursorIr {
+    /// The condition for the rows after the cursor, in the sort order;
+    /// `None` for the first page. `column_sql` renders a column, and
+    /// `value_sql` a value, in the order they appear in the sql.
+    ///
+    /// Sort keys can be null, which a row comparison doesn't handle, so a
+    /// row is after the cursor if, for some key, the keys before it are
+    /// equal to the cursor's and that key comes after the cursor's.
+    pub fn sql_string<C, V>(&self, column_sql: C, mut value_sql: V) -> Option<String>
+        where C: Fn(&str) -> String,
+              V: FnMut(&FilterValue) -> String,
+    {
+        let after = self.after.as_ref()?;
+
+        let comparison = match self.direction {
+            SortDirection::Asc => ">",
+            SortDirection::Desc => "<",
+        };
+
+        let mut alternatives = vec![];
+
+        for (idx, (column, value)) in self.columns.iter().zip(after).enumerate() {
+            // with nulls last, nothing comes after a null
+            if value.is_none() && self.nulls == NullsOrder::Last {
+                continue;
+            }
+
+            let mut conditions: Vec<_> = self.columns.iter().zip(after).take(idx)
+                .map(|(column, value)| {
+                    match value {
+                        Some(value) => format!("{} = {}", column_sql(column), value_sql(value)),
+                        None => format!("{} IS NULL", column_sql(column)),
+                    }
+                })
+                .collect();
+
+            let column = column_sql(column);
+            conditions.push(match (value, &self.nulls) {
+                (Some(value), NullsOrder::Last) => {
+                    format!("({} {} {} OR {} IS NULL)", column, comparison, value_sql(value), column)
+                },
+                (Some(value), NullsOrder::First) => {
+                    format!("{} {} {}", column, comparison, value_sql(value))
+                },
+                (None, _) => format!("{} IS NOT NULL", column),
+            });
+
+            alternatives.push(format!("({})", join(conditions, " AND ")));
+        }
+
+        if alternatives.is_empty() {
+            // the last key is the primary key, so this only happens if a
+            // primary key is null
+            return Some("1 = 0".to_owned());
+        }
+
+        Some(format!("({})", join(alternatives, " OR ")))
+    }
+}
+
+/// Shared by the backends' sql tests.
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use super::filter_ir::Compare;
+    use crate::backend::SqlQuery;
+
+    /// Filter values which would break out of the sql if they were spliced
+    /// into it unescaped.
+    pub const HOSTILE_VALUES: &[&str] = &[
+        "x' or '1'='1",
+        "x\\' or 1=1--",
+        "x\\",
+        "1 -- comment",
+        "1; drop table t",
+        "100%_",
+        "\\%",
+        "Zürich 'ß' 🦀",
+    ];
+
+    /// A query with one filter of every constraint, each with `value`, and
+    /// a cursor after `value`.
+    pub fn query_ir_with_every_constraint(value: &str) -> QueryIr {
+        let text = || FilterValue::Text(value.to_owned());
+        let filter = |constraint| FilterExpr::Filter(FilterIr {
+            column: "col".to_owned(),
+            constraint,
+        });
+
+        let string_match = |kind| filter(Constraint::StringMatch {
+            kind,
+            substring: value.to_owned(),
+        });
+
+        let filters = vec![
+            filter(Constraint::CompareList(vec![
+                Compare { comparison: Comparison::GreaterThan, n: text() },
+                Compare { comparison: Comparison::NotEqual, n: text() },
+            ])),
+            filter(Constraint::ExactMatch { pattern: text() }),
+            string_match(StringMatchKind::Contains),
+            string_match(StringMatchKind::StartsWith),
+            string_match(StringMatchKind::EndsWith),
+            string_match(StringMatchKind::ContainsCaseSensitive),
+            filter(Constraint::RegexMatch { regex: value.to_owned() }),
+            filter(Constraint::InArray { in_members: vec![text(), text()], not_in_members: vec![text()] }),
+            filter(Constraint::OneOf { in_members: vec![text(), text()], not_in_members: vec![text()] }),
+            FilterExpr::Or(vec![
+                filter(Constraint::IsNull),
+                FilterExpr::Not(Box::new(filter(Constraint::IsNotNull))),
+            ]),
+        ];
+
+        QueryIr {
+            table: "t".to_owned(),
+            projection: vec!["col".to_owned()],
+            filters,
+            sort: vec![SortIr { direction: SortDirection::Asc, column: "col".to_owned(), nulls: None }],
+            limit: Some(LimitQuery { offset: None, n: 10 }),
+            cursor: Some(CursorIr {
+                keys: vec!["col".to_owned()],
+                columns: vec!["col".to_owned()],
+                direction: SortDirection::Asc,
+                nulls: NullsOrder::Last,
+                after: Some(vec![Some(text())]),
+            }),
+        }
+    }
+
+    /// For backends that bind params: every value is bound, so the sql is
+    /// the same whatever the values are.
+    pub fn assert_only_bound(value: &str, sql_query: SqlQuery, benign: &SqlQuery) {
+        assert_eq!(sql_query.sql, benign.sql, "sql changed for value {:?}", value);
+        assert_eq!(sql_query.params.len(), benign.params.len());
+
+        for param in sql_query.params {
+            match param {
+                FilterValue::Text(ref s) => {
+                    assert!(matches_literally(s, value), "param {:?} for value {:?}", s, value);
+                },
+                _ => panic!("unexpected param {:?}", param),
+            }
+        }
+    }
+
+    /// Whether a value that was bound or rendered for the filter matches
+    /// `value` literally: either as is (or lowercased, for case-insensitive
+    /// matches), or as a LIKE pattern with `%` wildcards only at its ends.
+    pub fn matches_literally(rendered: &str, value: &str) -> bool {
+        let is_value = |s: &str| s == value || s == value.to_lowercase();
+
+        if is_value(rendered) {
+            return true;
+        }
+
+        // unescape the LIKE pattern, keeping track of the wildcards
+        let mut literal = String::new();
+        let mut wildcards = vec![];
+        let mut chars = rendered.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(escaped) => literal.push(escaped),
+                    None => return false,
+                },
+                '%' => wildcards.push(literal.chars().count()),
+                '_' => return false,
+                _ => literal.push(c),
+            }
+        }
+
+        let len = literal.chars().count();
+        let wildcards_at_ends = wildcards.iter().all(|&idx| idx == 0 || idx == len);
+
+        wildcards_at_ends && is_value(&literal)
+    }
 }
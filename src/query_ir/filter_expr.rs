@@ -0,0 +1,231 @@
+//! Boolean filter expressions, for filters which aren't all and-ed
+//! together, e.g. `?or=(city.eq.Boston,number_employees.gt.500)`.
+//!
+//! A condition is a param name, a `.`, and then the same filter syntax as
+//! for the param on its own. Conditions are grouped by `or(...)` and
+//! `and(...)`, and any condition or group can be negated by a leading
+//! `not.`. In the query, the key is the outermost group, e.g.
+//! `not.or=(...)`.
+//!
+//! Values containing `,`, `(` or `)` can be double quoted, e.g.
+//! `name."Store (Boston)"`; within quotes, `\` escapes the next char, e.g.
+//! `name."Store \"A\""`. A quote only opens a value at the start of a
+//! condition's value or right after a `.`, so that other quotes are kept as
+//! written. (A `compare` filter still takes a `,` as separating its
+//! comparisons.)
+
+use failure::{Error, bail, format_err};
+use itertools::join;
+
+use crate::schema::Interface;
+use super::FilterIr;
+
+/// Deeper nesting is rejected, so that a query can't recurse without bound.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone)]
+pub enum FilterExpr {
+    Filter(FilterIr),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+    Not(Box<FilterExpr>),
+}
+
+impl FilterExpr {
+    /// Whether a query param is a boolean filter expression instead of a
+    /// filter on one param. Params in the interface take precedence.
+    pub fn is_expr_key(name: &str, interface: &Interface) -> bool {
+        let key = name.trim_start_matches("not.");
+
+        (key == "or" || key == "and") && !interface.0.contains_key(name)
+    }
+
+    pub fn from_schema_query(key: &str, filter_query: &str, interface: &Interface) -> Result<Self, Error> {
+        parse_expr(&format!("{}{}", key, filter_query), interface, 0)
+    }
+
+    /// Renders the expression, with `filter_sql` rendering each filter.
+    ///
+    /// Groups are parenthesized, as are their members, since a filter's
+    /// sql may itself be several conditions.
+    pub fn sql_string<F: FnMut(&FilterIr) -> String>(&self, filter_sql: &mut F) -> String {
+        match self {
+            FilterExpr::Filter(f) => filter_sql(f),
+            FilterExpr::And(exprs) => group_sql(exprs, " and ", filter_sql),
+            FilterExpr::Or(exprs) => group_sql(exprs, " or ", filter_sql),
+            FilterExpr::Not(expr) => format!("not ({})", expr.sql_string(filter_sql)),
+        }
+    }
+}
+
+fn group_sql<F: FnMut(&FilterIr) -> String>(exprs: &[FilterExpr], op: &str, filter_sql: &mut F) -> String {
+    let members: Vec<_> = exprs.iter()
+        .map(|expr| format!("({})", expr.sql_string(filter_sql)))
+        .collect();
+
+    format!("({})", join(members, op))
+}
+
+fn parse_expr(s: &str, interface: &Interface, depth: usize) -> Result<FilterExpr, Error> {
+    if depth > MAX_DEPTH {
+        bail!("Filter expression is nested more than {} deep", MAX_DEPTH);
+    }
+
+    if s.starts_with("not.") {
+        let expr = parse_expr(&s[4..], interface, depth + 1)?;
+        return Ok(FilterExpr::Not(Box::new(expr)));
+    }
+
+    for (op, is_or) in &[("or(", true), ("and(", false)] {
+        if s.starts_with(op) {
+            if !s.ends_with(')') {
+                bail!("Unbalanced parentheses in filter expression");
+            }
+
+            let members = split_members(&s[op.len()..s.len() - 1])?
+                .iter()
+                .map(|member| parse_expr(member, interface, depth + 1))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            return Ok(if *is_or {
+                FilterExpr::Or(members)
+            } else {
+                FilterExpr::And(members)
+            });
+        }
+    }
+
+    let mut parts = s.splitn(2, '.');
+    let name = parts.next().unwrap_or_default();
+    let filter_query = parts.next()
+        .ok_or_else(|| format_err!("Could not parse filter expression condition '{}'", s))?;
+
+    let filter = FilterIr::from_schema_query(name, &unquote(filter_query), interface)?;
+
+    Ok(FilterExpr::Filter(filter))
+}
+
+/// Splits a group's members on the commas which aren't quoted or in a
+/// nested group.
+fn split_members(s: &str) -> Result<Vec<String>, Error> {
+    let mut members = vec![];
+    let mut member = String::new();
+    let mut depth = 0;
+    let mut in_quotes = false;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if in_quotes => {
+                member.push(c);
+                if let Some(escaped) = chars.next() {
+                    member.push(escaped);
+                }
+                continue;
+            },
+            '"' if in_quotes => in_quotes = false,
+            '"' if opens_quote(&member) => in_quotes = true,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes => {
+                if depth == 0 {
+                    bail!("Unbalanced parentheses in filter expression");
+                }
+                depth -= 1;
+            },
+            ',' if !in_quotes && depth == 0 => {
+                members.push(std::mem::replace(&mut member, String::new()));
+                continue;
+            },
+            _ => (),
+        }
+
+        member.push(c);
+    }
+
+    if in_quotes || depth != 0 {
+        bail!("Unbalanced quotes or parentheses in filter expression");
+    }
+
+    members.push(member);
+
+    if members.iter().any(|member| member.is_empty()) {
+        bail!("Blank member not allowed in filter expression");
+    }
+
+    Ok(members)
+}
+
+/// Whether a `"` following `prefix` opens a quoted value.
+fn opens_quote(prefix: &str) -> bool {
+    prefix.is_empty() || prefix.ends_with('.')
+}
+
+/// Removes the quotes around a quoted value, and the backslashes escaping
+/// chars within them. Only a pair enclosing the rest of the condition is
+/// removed; otherwise it's returned as written.
+fn unquote(s: &str) -> String {
+    let start = s.char_indices()
+        .find(|&(i, c)| c == '"' && opens_quote(&s[..i]))
+        .map(|(i, _)| i);
+
+    let start = match start {
+        Some(i) => i,
+        None => return s.to_owned(),
+    };
+
+    let mut res = s[..start].to_owned();
+    let mut chars = s[start + 1..].chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                // the closing quote must end the value
+                return if chars.as_str().is_empty() {
+                    res
+                } else {
+                    s.to_owned()
+                };
+            },
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    res.push(escaped);
+                }
+            },
+            _ => res.push(c),
+        }
+    }
+
+    // unterminated
+    s.to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_the_outer_quotes_are_removed() {
+        let cases = [
+            (r#""Store (Boston)""#, "Store (Boston)"),
+            (r#"eq."Boston, MA""#, "eq.Boston, MA"),
+            (r#""Store \"A\"""#, r#"Store "A""#),
+            (r#""back\\slash""#, r#"back\slash"#),
+            (r#"Store "A""#, r#"Store "A""#),
+            (r#"6" pipe"#, r#"6" pipe"#),
+            (r#""a"b""#, r#""a"b""#),
+            (r#""open"#, r#""open"#),
+            ("Store", "Store"),
+        ];
+
+        for (quoted, expected) in &cases {
+            assert_eq!(unquote(quoted), *expected, "{}", quoted);
+        }
+    }
+
+    #[test]
+    fn quoted_members_keep_their_commas() {
+        let members = split_members(r#"name."a,\"b\"",name.6" pipe,city.c"#).unwrap();
+
+        assert_eq!(members, vec![r#"name."a,\"b\"""#, r#"name.6" pipe"#, "city.c"]);
+    }
+}
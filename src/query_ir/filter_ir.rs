@@ -1,14 +1,19 @@
-use failure::{Error, format_err, bail};
+use chrono::{NaiveDate, NaiveDateTime};
+use failure::{Error, Fail, format_err, bail};
 use itertools::join;
+use std::fmt;
 use std::str::FromStr;
 
-use crate::schema::{Interface, FilterType};
+use crate::schema::{Interface, FilterType, ParamType};
+
+/// The longest regex a `match.` filter can have. Regexes run on the
+/// database, so this keeps a client from sending an expensive one.
+pub const MAX_REGEX_LEN: usize = 100;
 
 #[derive(Debug, Clone)]
 pub struct FilterIr {
     pub column: String,
     pub constraint: Constraint,
-    pub is_text: bool,
 }
 
 impl FilterIr {
@@ -17,95 +22,417 @@ impl FilterIr {
         let interface_param_value = interface.0.get(name)
             .ok_or_else(|| format_err!("No endpoint param found for filter '{}'", name))?;
         let column = interface_param_value.column.clone();
-        let is_text = interface_param_value.is_text;
+        let param_type = &interface_param_value.param_type;
         let filter_type = &interface_param_value.filter_type;
 
+        let parse_value = |s: &str| {
+            FilterValue::parse(s, param_type)
+                .ok_or_else(|| FilterValueError::new(name, s, param_type))
+        };
+
+        // comma separated members; those with a leading `~` are excluded
+        let parse_members = |s: &str| -> Result<(Vec<FilterValue>, Vec<FilterValue>), Error> {
+            let mut in_members = vec![];
+            let mut not_in_members = vec![];
+
+            for member in s.split(",") {
+                let leading_char = member.chars()
+                    .nth(0)
+                    .ok_or(format_err!("blank member not allowed"))?;
+
+                if leading_char == '~' {
+                    let stripped_member: String = member.chars()
+                        .skip(1)
+                        .collect();
+                    not_in_members.push(parse_value(&stripped_member)?);
+                } else {
+                    in_members.push(parse_value(member)?);
+                }
+            }
+
+            Ok((in_members, not_in_members))
+        };
+
+        // null checks work the same for every filter type. Other filters
+        // never match nulls, as in sql (so `neq.1` doesn't match a null)
+        let null_constraint = match filter_query {
+            "is.null" => Some(Constraint::IsNull),
+            "not.null" => Some(Constraint::IsNotNull),
+            _ => None,
+        };
+        if let Some(constraint) = null_constraint {
+            return Ok(FilterIr {
+                column,
+                constraint,
+            });
+        }
 
         let constraint = match filter_type {
             FilterType::Compare => {
                 // allow multiple comparisons, separated by commas
-                let comparisons = filter_query.split(",")
-                    .map(|one_comparison_str| {
-                        match &one_comparison_str.split(".").collect::<Vec<_>>()[..] {
-                            [constraint_type, members @ ..] => {
-                                let comparison = constraint_type.parse::<Comparison>()?;
-                                let n = join(members, "."); // doesn't check for malformed
-
-                                Ok(Compare {
-                                    comparison,
-                                    n,
-                                })
-                            },
-                            _ => Err(format_err!("Could not parse a Comparison for filter {}", name)),
-                        }
-                    })
-                    .collect::<Result<Vec<_>,_>>()?;
+                let mut comparisons = vec![];
+
+                for one_comparison_str in split_comparisons(filter_query) {
+                    if one_comparison_str.starts_with("between.") {
+                        comparisons.extend(parse_between(name, &one_comparison_str[8..], &parse_value)?);
+                        continue;
+                    }
+                    if one_comparison_str.starts_with("range.") {
+                        comparisons.extend(parse_range(name, &one_comparison_str[6..], &parse_value)?);
+                        continue;
+                    }
+
+                    match &one_comparison_str.split(".").collect::<Vec<_>>()[..] {
+                        [constraint_type, members @ ..] => {
+                            let comparison = constraint_type.parse::<Comparison>()?;
+                            let n = parse_value(&join(members, "."))?;
+
+                            comparisons.push(Compare {
+                                comparison,
+                                n,
+                            });
+                        },
+                        _ => bail!("Could not parse a Comparison for filter {}", name),
+                    }
+                }
 
                 Constraint::CompareList(comparisons)
             },
             FilterType::ExactMatch => {
+                let pattern = parse_value(filter_query)?;
+
                 Constraint::ExactMatch {
-                    pattern: filter_query.to_owned(),
+                    pattern,
+                }
+            },
+            FilterType::StringMatch if filter_query.starts_with("match.") => {
+                // regexes run on the database, so they're opt-in and short
+                if !interface_param_value.allow_regex {
+                    bail!("Regex match not allowed for filter {}", name);
+                }
+
+                let regex = &filter_query[6..];
+                if regex.is_empty() {
+                    bail!("Blank regex match not allowed for filter {}", name);
+                }
+                if regex.chars().count() > MAX_REGEX_LEN {
+                    bail!("Regex for filter {} is longer than {} chars", name, MAX_REGEX_LEN);
+                }
+
+                Constraint::RegexMatch {
+                    regex: regex.to_owned(),
                 }
             },
             FilterType::StringMatch => {
+                // a value with no operator is a case-insensitive contains
+                let (kind, substring) = [
+                    ("starts.", StringMatchKind::StartsWith),
+                    ("ends.", StringMatchKind::EndsWith),
+                    ("contains.", StringMatchKind::ContainsCaseSensitive),
+                ].iter()
+                    .find(|(op, _)| filter_query.starts_with(op))
+                    .map(|(op, kind)| (kind.clone(), &filter_query[op.len()..]))
+                    .unwrap_or((StringMatchKind::Contains, filter_query));
+
+                if substring.is_empty() {
+                    bail!("Blank string match not allowed for filter {}", name);
+                }
+
                 Constraint::StringMatch {
-                    substring: filter_query.to_owned(),
+                    kind,
+                    substring: substring.to_owned(),
                 }
             },
             FilterType::InArray => {
-                let mut in_members = vec![];
-                let mut not_in_members = vec![];
-
-                for member in filter_query.split(",") {
-                    let leading_char = member.chars()
-                        .nth(0)
-                        .ok_or(format_err!("blank member not allowed"))?;
-                    println!("{}", leading_char);
-
-                    if leading_char == '~' {
-                        let stripped_member = member.chars()
-                            .skip(1)
-                            .collect();
-                        not_in_members.push(stripped_member);
-                    } else {
-                        in_members.push(member.to_string());
-                    }
-                }
+                let (in_members, not_in_members) = parse_members(filter_query)?;
 
                 Constraint::InArray {
                     in_members,
                     not_in_members,
                 }
-            }
+            },
+            FilterType::OneOf => {
+                let (in_members, not_in_members) = parse_members(filter_query)?;
+
+                let max_members = interface_param_value.max_members;
+                if in_members.len() + not_in_members.len() > max_members {
+                    bail!("Filter '{}' has more than {} members", name, max_members);
+                }
+
+                Constraint::OneOf {
+                    in_members,
+                    not_in_members,
+                }
+            },
         };
 
         Ok(FilterIr {
             column,
             constraint,
-            is_text,
         })
     }
 }
+
+/// Splits a compare filter's comparisons on commas, except for the comma
+/// between the bounds of a range.
+fn split_comparisons(filter_query: &str) -> Vec<String> {
+    let mut comparisons: Vec<String> = vec![];
+    let mut in_range = false;
+
+    for part in filter_query.split(",") {
+        match comparisons.last_mut() {
+            Some(range) if in_range => {
+                range.push(',');
+                range.push_str(part);
+                in_range = false;
+            },
+            _ => {
+                comparisons.push(part.to_owned());
+                in_range = part.starts_with("range.");
+            },
+        }
+    }
+
+    comparisons
+}
+
+/// `between.low.high` is inclusive of both bounds. Values may themselves
+/// contain a `.` (e.g. floats), so it's split where both sides are valid
+/// values; if that's ambiguous, `range` must be used instead.
+fn parse_between<F>(name: &str, s: &str, parse_value: &F) -> Result<Vec<Compare>, Error>
+    where F: Fn(&str) -> Result<FilterValue, FilterValueError>
+{
+    let bounds: Vec<_> = s.match_indices('.')
+        .filter_map(|(idx, _)| {
+            let low = parse_value(&s[..idx]).ok()?;
+            let high = parse_value(&s[idx + 1..]).ok()?;
+            Some((low, high))
+        })
+        .collect();
+
+    match &bounds[..] {
+        [(low, high)] => {
+            Ok(vec![
+                Compare { comparison: Comparison::GreaterThanOrEqual, n: low.clone() },
+                Compare { comparison: Comparison::LessThanOrEqual, n: high.clone() },
+            ])
+        },
+        [] => bail!("Could not parse between for filter {}; expected between.low.high", name),
+        _ => bail!("Ambiguous between for filter {}; use range.[low,high] instead", name),
+    }
+}
+
+/// `range.[low,high)`, where `[`/`]` include the bound and `(`/`)` exclude
+/// it. Either bound can be left out, e.g. `range.[2019-01-01,)`.
+fn parse_range<F>(name: &str, s: &str, parse_value: &F) -> Result<Vec<Compare>, Error>
+    where F: Fn(&str) -> Result<FilterValue, FilterValueError>
+{
+    let invalid = || format_err!("Could not parse range for filter {}; expected e.g. range.[low,high)", name);
+
+    let low_comparison = match s.chars().next() {
+        Some('[') => Comparison::GreaterThanOrEqual,
+        Some('(') => Comparison::GreaterThan,
+        _ => return Err(invalid()),
+    };
+    let high_comparison = match s.chars().last() {
+        Some(']') if s.len() > 1 => Comparison::LessThanOrEqual,
+        Some(')') if s.len() > 1 => Comparison::LessThan,
+        _ => return Err(invalid()),
+    };
+
+    let mut bounds = s[1..s.len() - 1].splitn(2, ',');
+    let low = bounds.next().unwrap_or_default();
+    let high = bounds.next().ok_or_else(invalid)?;
+
+    if low.is_empty() && high.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut comparisons = vec![];
+    if !low.is_empty() {
+        comparisons.push(Compare { comparison: low_comparison, n: parse_value(low)? });
+    }
+    if !high.is_empty() {
+        comparisons.push(Compare { comparison: high_comparison, n: parse_value(high)? });
+    }
+
+    Ok(comparisons)
+}
+
 #[derive(Debug, Clone)]
 pub enum Constraint {
     CompareList(Vec<Compare>),
     ExactMatch {
-        pattern: String,
+        pattern: FilterValue,
     },
     StringMatch {
+        kind: StringMatchKind,
         substring: String,
     },
+    /// The regex is in the backend's own dialect.
+    RegexMatch {
+        regex: String,
+    },
     InArray {
-        in_members: Vec<String>,
-        not_in_members: Vec<String>,
+        in_members: Vec<FilterValue>,
+        not_in_members: Vec<FilterValue>,
     },
+    /// For scalar columns; the column is one of `in_members` (if any), and
+    /// none of `not_in_members`.
+    OneOf {
+        in_members: Vec<FilterValue>,
+        not_in_members: Vec<FilterValue>,
+    },
+    IsNull,
+    IsNotNull,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StringMatchKind {
+    /// case-insensitive
+    Contains,
+    /// case-insensitive
+    StartsWith,
+    /// case-insensitive
+    EndsWith,
+    ContainsCaseSensitive,
+}
+
+impl StringMatchKind {
+    /// The LIKE pattern for the substring, with `%`, `_` and `\` escaped
+    /// by a `\` so that they match literally.
+    pub fn like_pattern(&self, substring: &str) -> String {
+        let escaped = escape_like(substring);
+
+        match self {
+            StringMatchKind::Contains |
+            StringMatchKind::ContainsCaseSensitive => format!("%{}%", escaped),
+            StringMatchKind::StartsWith => format!("{}%", escaped),
+            StringMatchKind::EndsWith => format!("%{}", escaped),
+        }
+    }
+}
+
+fn escape_like(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if c == '\\' || c == '%' || c == '_' {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+
+    res
 }
 
 #[derive(Debug, Clone)]
 pub struct Compare {
     pub comparison: Comparison,
-    pub n: String, // to allow all numbers
+    pub n: FilterValue,
+}
+
+/// A value from a filter query, typed so that backends can bind it as a
+/// parameter (or safely render it as a literal) instead of splicing the
+/// raw query string into sql.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterValue {
+    Text(String),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+    Bool(bool),
+    Decimal(String), // validated, but kept as a string to be lossless
+}
+
+impl FilterValue {
+    /// Parses a value from the query as the param's type. Returns `None`
+    /// if it's not a valid value of that type.
+    pub fn parse(s: &str, param_type: &ParamType) -> Option<Self> {
+        match param_type {
+            ParamType::Text => Some(FilterValue::Text(s.to_owned())),
+            ParamType::Int => s.parse().ok().map(FilterValue::Int),
+            ParamType::UInt => s.parse().ok().map(FilterValue::UInt),
+            ParamType::Float => parse_float(s).map(FilterValue::Float),
+            ParamType::Number => {
+                s.parse().ok().map(FilterValue::Int)
+                    .or_else(|| s.parse().ok().map(FilterValue::UInt))
+                    .or_else(|| parse_float(s).map(FilterValue::Float))
+            },
+            ParamType::Date => {
+                NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()
+                    .map(FilterValue::Date)
+            },
+            ParamType::DateTime => {
+                NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f").ok()
+                    .or_else(|| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").ok())
+                    .map(FilterValue::DateTime)
+            },
+            ParamType::Bool => {
+                match s {
+                    "true" | "1" => Some(FilterValue::Bool(true)),
+                    "false" | "0" => Some(FilterValue::Bool(false)),
+                    _ => None,
+                }
+            },
+            ParamType::Decimal => {
+                let digits = s.strip_prefix('-').unwrap_or(s);
+                let mut parts = digits.splitn(2, '.');
+                let is_digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+
+                let int_part_ok = parts.next().map(is_digits).unwrap_or(false);
+                let frac_part_ok = parts.next().map(is_digits).unwrap_or(true);
+
+                if int_part_ok && frac_part_ok {
+                    Some(FilterValue::Decimal(s.to_owned()))
+                } else {
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// f64 parsing also accepts "inf" and "NaN", which aren't valid in sql.
+fn parse_float(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+impl fmt::Display for FilterValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterValue::Text(s) => write!(f, "{}", s),
+            FilterValue::Int(n) => write!(f, "{}", n),
+            FilterValue::UInt(n) => write!(f, "{}", n),
+            FilterValue::Float(n) => write!(f, "{}", n),
+            FilterValue::Date(d) => write!(f, "{}", d.format("%Y-%m-%d")),
+            FilterValue::DateTime(dt) => write!(f, "{}", dt.format("%Y-%m-%d %H:%M:%S%.f")),
+            FilterValue::Bool(b) => write!(f, "{}", b),
+            FilterValue::Decimal(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// A filter value which doesn't match the type declared for its param.
+/// This is the client's mistake, so it's returned as a 400.
+#[derive(Debug, Fail)]
+#[fail(display = "Filter '{}' expected a value of type {}, but got '{}'", param, expected, value)]
+pub struct FilterValueError {
+    pub param: String,
+    pub value: String,
+    pub expected: ParamType,
+}
+
+impl FilterValueError {
+    pub fn new(param: &str, value: &str, expected: &ParamType) -> Self {
+        FilterValueError {
+            param: param.to_owned(),
+            value: value.to_owned(),
+            expected: expected.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -147,4 +474,90 @@ impl FromStr for Comparison {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use indexmap::IndexMap;
+
+    use super::*;
+    use crate::schema::ParamValue;
+
+    fn interface(filter_type: FilterType, param_type: ParamType) -> Interface {
+        let mut params = IndexMap::new();
+        params.insert("n".to_owned(), ParamValue {
+            column: "n".to_owned(),
+            filter_type,
+            param_type,
+            visible: true,
+            dimension: None,
+            is_text: false,
+            is_template_var: false,
+            max_members: 10,
+            allow_regex: false,
+        });
+
+        Interface(params)
+    }
+
+    #[test]
+    fn parse_decimal() {
+        let parse = |s| FilterValue::parse(s, &ParamType::Decimal);
+
+        assert_eq!(parse("5"), Some(FilterValue::Decimal("5".to_owned())));
+        assert_eq!(parse("-5.25"), Some(FilterValue::Decimal("-5.25".to_owned())));
 
+        // `--` would start a sql comment where decimals are rendered unquoted
+        assert_eq!(parse("--5"), None);
+        assert_eq!(parse("-"), None);
+        assert_eq!(parse("1."), None);
+        assert_eq!(parse(".5"), None);
+        assert_eq!(parse("1e5"), None);
+    }
+
+    #[test]
+    fn hostile_non_text_values_are_rejected() {
+        let param_types = [
+            ParamType::Int,
+            ParamType::UInt,
+            ParamType::Float,
+            ParamType::Number,
+            ParamType::Decimal,
+            ParamType::Date,
+            ParamType::DateTime,
+            ParamType::Bool,
+        ];
+        let values = ["--1", "1--", "1 or 1=1", "1;select 1", "1'", "1\\", "１"];
+
+        for param_type in &param_types {
+            let interface = interface(FilterType::Compare, param_type.clone());
+
+            for value in &values {
+                let filter_query = format!("gt.{}", value);
+                let res = FilterIr::from_schema_query("n", &filter_query, &interface);
+
+                assert!(res.is_err(), "{} accepted for {}", filter_query, param_type);
+            }
+        }
+    }
+
+    #[test]
+    fn regex_match() {
+        let mut interface = interface(FilterType::StringMatch, ParamType::Text);
+
+        // `match.` is rejected unless the param allows regexes
+        assert!(FilterIr::from_schema_query("n", "match.^a", &interface).is_err());
+
+        interface.0.get_mut("n").unwrap().allow_regex = true;
+
+        let filter = FilterIr::from_schema_query("n", "match.^a", &interface).unwrap();
+        match filter.constraint {
+            Constraint::RegexMatch { ref regex } => assert_eq!(regex, "^a"),
+            ref constraint => panic!("expected a regex match, got {:?}", constraint),
+        }
+
+        let longest = format!("match.{}", "ß".repeat(MAX_REGEX_LEN));
+        let too_long = format!("match.{}", "ß".repeat(MAX_REGEX_LEN + 1));
+        assert!(FilterIr::from_schema_query("n", &longest, &interface).is_ok());
+        assert!(FilterIr::from_schema_query("n", &too_long, &interface).is_err());
+        assert!(FilterIr::from_schema_query("n", "match.", &interface).is_err());
+    }
+}
@@ -6,16 +6,22 @@ use log::warn;
 use serde_derive::{Deserialize, Serialize};
 use serde_json;
 use std::convert::From;
+use std::fmt;
 use std::fs;
 use std::sync::{Arc, RwLock};
 use tera::{Tera, Context};
 
 use schema_config::*;
+use crate::format::CsvOptions;
 use crate::middleware::X_BERYL_SECRET;
-use crate::query::Query;
+use crate::query::{CursorQuery, LimitQuery, NullsOrder, Query, SortDirection};
 use crate::query_ir::{
     QueryIr,
+    CursorIr,
+    FilterExpr,
     FilterIr,
+    FilterValue,
+    FilterValueError,
     SortIr,
 };
 
@@ -30,6 +36,8 @@ impl Schema {
         let config_str = fs::read_to_string(path)?;
         let schema_config: SchemaConfig = serde_json::from_str(&config_str)?;
 
+        check_template_var_types(&schema_config)?;
+
         Ok(schema_config.into())
     }
     pub fn get_endpoint(&self, endpoint_path: &str) -> Option<Endpoint> {
@@ -82,6 +90,9 @@ impl Schema {
 
 
                 for (k, v) in context_vars {
+                    let param_type = &schema_endpoint.interface.0[k].param_type;
+                    let v = validate_template_var(k, v, param_type)?;
+
                     context.insert(&k, &v);
                 }
 
@@ -103,23 +114,42 @@ impl Schema {
             }
         };
 
-        // projection is all interface names where visible is true
-        let projection = schema_endpoint.interface.0.iter()
-            .filter(|(_, param_value)| {
-                param_value.visible
-            })
+        // the requested fields, or else all interface params where visible
+        // is true
+        let output_params: Vec<_> = if let Some(ref fields) = query.fields {
+            let mut output_params = vec![];
+
+            for name in &fields.0 {
+                let param_value = schema_endpoint.interface.0.get(name)
+                    .filter(|param_value| param_value.visible)
+                    .ok_or_else(|| format_err!("field '{}' is not in endpoint", name))?;
+
+                if output_params.iter().any(|(param_key, _)| *param_key == name) {
+                    bail!("field '{}' is requested more than once", name);
+                }
+
+                output_params.push((name, param_value));
+            }
+
+            output_params
+        } else {
+            schema_endpoint.interface.0.iter()
+                .filter(|(_, param_value)| {
+                    param_value.visible
+                })
+                .collect()
+        };
+
+        let mut projection: Vec<_> = output_params.iter()
             .map(|(_, param_value)| {
                 param_value.column.clone()
             })
             .collect();
 
         // headers
-        let headers = schema_endpoint.interface.0.iter()
-            .filter(|(_, param_value)| {
-                param_value.visible
-            })
+        let headers = output_params.iter()
             .map(|(param_key, _)| {
-                param_key.clone()
+                (*param_key).clone()
             })
             .collect();
 
@@ -130,24 +160,42 @@ impl Schema {
                 name != &X_BERYL_SECRET
             })
             .map(|(name, filter_query)| {
-                FilterIr::from_schema_query(name, filter_query, &schema_endpoint.interface)
+                if FilterExpr::is_expr_key(name, &schema_endpoint.interface) {
+                    FilterExpr::from_schema_query(name, filter_query, &schema_endpoint.interface)
+                } else {
+                    FilterIr::from_schema_query(name, filter_query, &schema_endpoint.interface)
+                        .map(FilterExpr::Filter)
+                }
             })
             .collect();
         let filters = filters?;
 
-        let sort = if let Some(ref s) = query.sort {
-            let column = schema_endpoint
-                .interface
-                .0.get(&s.name)
-                .map(|interface_param_value| {
-                    interface_param_value.column.clone()
-                })
-                .ok_or_else(|| format_err!("query filter name not in schema"))?;
+        let sort: Result<_, Error> = query.sort.iter()
+            .map(|s| {
+                let column = schema_endpoint
+                    .interface
+                    .0.get(&s.name)
+                    .map(|interface_param_value| {
+                        interface_param_value.column.clone()
+                    })
+                    .ok_or_else(|| format_err!("query filter name not in schema"))?;
 
-            Some(SortIr {
-                direction: s.direction.clone(),
-                column,
+                Ok(SortIr {
+                    direction: s.direction.clone(),
+                    column,
+                    nulls: s.nulls.clone(),
+                })
             })
+            .collect();
+        let mut sort = sort?;
+
+        let cursor = if let Some(ref cursor_query) = query.cursor {
+            let cursor = gen_cursor_ir(schema_endpoint, query, cursor_query, &mut sort)?;
+
+            // selected after the output columns, to read the next cursor from
+            projection.extend(cursor.columns.iter().cloned());
+
+            Some(cursor)
         } else {
             None
         };
@@ -159,18 +207,164 @@ impl Schema {
                 filters,
                 sort,
                 limit: query.limit.clone(),
+                cursor,
             },
             headers
         ))
     }
 }
 
+/// Keyset pagination needs every sort key to have the same direction and
+/// nulls order. Nulls go last unless the sort says otherwise, and the
+/// order is always given to the database, since the default differs
+/// between databases. The primary key is appended to the sort (if it's not
+/// already a key), so that the order is total.
+fn gen_cursor_ir(
+    schema_endpoint: &Endpoint,
+    query: &Query,
+    cursor_query: &CursorQuery,
+    sort: &mut Vec<SortIr>,
+    ) -> Result<CursorIr, Error>
+{
+    let primary = schema_endpoint.primary.as_ref()
+        .ok_or_else(|| format_err!("A cursor needs the endpoint to have a primary key"))?;
+
+    match query.limit {
+        Some(LimitQuery { offset: None, .. }) => (),
+        Some(_) => bail!("A cursor can't be used with an offset"),
+        None => bail!("A cursor needs a limit"),
+    }
+
+    let direction = query.sort.first()
+        .map(|s| s.direction.clone())
+        .unwrap_or(SortDirection::Asc);
+
+    if query.sort.iter().any(|s| s.direction != direction) {
+        bail!("A cursor needs all sort keys in the same direction");
+    }
+
+    let nulls = query.sort.first()
+        .and_then(|s| s.nulls.clone())
+        .unwrap_or(NullsOrder::Last);
+
+    if query.sort.iter().any(|s| s.nulls.as_ref().unwrap_or(&NullsOrder::Last) != &nulls) {
+        bail!("A cursor needs all sort keys to have the same nulls order");
+    }
+
+    for srt in sort.iter_mut() {
+        srt.nulls = Some(nulls.clone());
+    }
+
+    let mut keys: Vec<_> = query.sort.iter()
+        .map(|s| s.name.clone())
+        .collect();
+
+    let primary_column = schema_endpoint.interface.0.get(primary)
+        .map(|param_value| param_value.column.clone())
+        .ok_or_else(|| format_err!("Primary key is not in the endpoint"))?;
+
+    if !keys.contains(primary) {
+        keys.push(primary.clone());
+        sort.push(SortIr {
+            direction: direction.clone(),
+            column: primary_column,
+            nulls: Some(nulls.clone()),
+        });
+    }
+
+    let params: Vec<_> = keys.iter()
+        .map(|key| {
+            schema_endpoint.interface.0.get(key)
+                .ok_or_else(|| format_err!("query filter name not in schema"))
+        })
+        .collect::<Result<_, _>>()?;
+
+    let columns = params.iter()
+        .map(|param_value| param_value.column.clone())
+        .collect();
+
+    let after = match cursor_query {
+        CursorQuery::Start => None,
+        CursorQuery::After(cursor) => {
+            if cursor.keys != keys {
+                bail!("Cursor is for a different sort");
+            }
+
+            let values = cursor.values.iter()
+                .zip(&params)
+                .map(|(value, param_value)| {
+                    match value {
+                        Some(value) => {
+                            FilterValue::parse(value, &param_value.param_type)
+                                .map(Some)
+                                .ok_or_else(|| format_err!("Could not parse cursor"))
+                        },
+                        None => Ok(None),
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+
+            Some(values)
+        },
+    };
+
+    Ok(CursorIr {
+        keys,
+        columns,
+        direction,
+        nulls,
+        after,
+    })
+}
+
+/// A template var is validated by its type, so the type must be declared
+/// (by `type` or `is_text`) rather than defaulting to a number.
+fn check_template_var_types(config: &SchemaConfig) -> Result<(), Error> {
+    for endpoint in &config.endpoints {
+        for (name, param) in &endpoint.interface.0 {
+            let is_template_var = param.is_template_var.unwrap_or(false);
+            let is_typed = param.param_type.is_some() || param.is_text.is_some();
+
+            if is_template_var && !is_typed {
+                bail!("Template var '{}' of endpoint '{}' must declare its `type` or `is_text`",
+                    name,
+                    endpoint.name,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Template vars are rendered straight into the sql, so unlike filters they
+/// can't be bound as params. Instead, values must parse as the param's type,
+/// and text may not contain any character which could end a quoted literal.
+fn validate_template_var(name: &str, value: &str, param_type: &ParamType) -> Result<String, Error> {
+    if *param_type == ParamType::Text {
+        if value.contains(|c| c == '\'' || c == '"' || c == '`' || c == '\\') {
+            bail!("Template var '{}' may not contain quotes or backslashes", name);
+        }
+        return Ok(value.to_owned());
+    }
+
+    let value = FilterValue::parse(value, param_type)
+        .ok_or_else(|| FilterValueError::new(name, value, param_type))?;
+
+    Ok(value.to_string())
+}
+
+/// Default for the most members a `one_of` filter can have, which keeps the
+/// sql (and the number of bound params) to a reasonable size.
+pub const DEFAULT_MAX_MEMBERS: usize = 1000;
+
 #[derive(Debug, Clone)]
 pub struct Endpoint{
     pub name: String,
     pub sql_select: SqlSelect,
     pub primary:Option<String>,
     pub interface: Interface,
+    pub csv: CsvOptions,
 }
 
 #[derive(Debug, Clone)]
@@ -180,10 +374,15 @@ pub struct Interface(pub IndexMap<ParamKey, ParamValue>);
 pub struct ParamValue {
     pub column: String,
     pub filter_type: FilterType,
+    pub param_type: ParamType,
     pub visible: bool,
     pub dimension: Option<Dimension>,
     pub is_text: bool,
     pub is_template_var: bool,
+    /// The most members a `one_of` filter can have.
+    pub max_members: usize,
+    /// Whether a `string_match` filter accepts `match.regex`.
+    pub allow_regex: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -204,6 +403,49 @@ pub enum FilterType {
     StringMatch,
     #[serde(rename="in_array")]
     InArray,
+    #[serde(rename="one_of")]
+    OneOf,
+}
+
+/// The type of a param's column, used to parse and validate filter values
+/// before they reach the database.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum ParamType {
+    #[serde(rename="int")]
+    Int,
+    #[serde(rename="uint")]
+    UInt,
+    #[serde(rename="float")]
+    Float,
+    /// Any int or float. The default when `is_text` is false.
+    #[serde(rename="number")]
+    Number,
+    #[serde(rename="text")]
+    Text,
+    #[serde(rename="date")]
+    Date,
+    #[serde(rename="datetime")]
+    DateTime,
+    #[serde(rename="bool")]
+    Bool,
+    #[serde(rename="decimal")]
+    Decimal,
+}
+
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParamType::Int => write!(f, "int"),
+            ParamType::UInt => write!(f, "uint"),
+            ParamType::Float => write!(f, "float"),
+            ParamType::Number => write!(f, "number"),
+            ParamType::Text => write!(f, "text"),
+            ParamType::Date => write!(f, "date (YYYY-MM-DD)"),
+            ParamType::DateTime => write!(f, "datetime (YYYY-MM-DDTHH:MM:SS)"),
+            ParamType::Bool => write!(f, "bool"),
+            ParamType::Decimal => write!(f, "decimal"),
+        }
+    }
 }
 
 impl From<SchemaConfig> for Schema {
@@ -222,6 +464,7 @@ impl From<EndpointConfig> for Endpoint {
             sql_select: config.sql_select.into(),
             primary: config.primary,
             interface: config.interface.into(),
+            csv: config.csv.unwrap_or_default(),
         }
     }
 }
@@ -231,14 +474,24 @@ impl From<InterfaceConfig> for Interface {
     fn from(config: InterfaceConfig) -> Self {
         let res = config.0.iter()
             .map(|(param_key, p_config)| {
+                let is_text = p_config.is_text.unwrap_or(false);
+                let default_param_type = if is_text {
+                    ParamType::Text
+                } else {
+                    ParamType::Number
+                };
+
                 (param_key.clone(),
                  ParamValue {
                      column: p_config.column.clone().unwrap_or(param_key.to_owned()),
                      filter_type: p_config.filter_type.clone().unwrap_or(FilterType::Compare),
+                     param_type: p_config.param_type.clone().unwrap_or(default_param_type),
                      visible: p_config.visible.unwrap_or(true),
                      dimension: p_config.dimension.clone().map(|d| d.into()),
-                     is_text: p_config.is_text.unwrap_or(false),
+                     is_text,
                      is_template_var: p_config.is_template_var.unwrap_or(false),
+                     max_members: p_config.max_members.unwrap_or(DEFAULT_MAX_MEMBERS),
+                     allow_regex: p_config.allow_regex.unwrap_or(false),
                  },
                 )
             }).collect();
@@ -280,3 +533,34 @@ impl From<SqlSelectConfig> for SqlSelect {
 
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(template_var: &str) -> SchemaConfig {
+        let config = format!(r#"{{"endpoints": [{{
+            "name": "managers",
+            "sql_select": {{"template": {{"template_path": "managers.sql"}}}},
+            "interface": {{"manager_id": {}}}
+        }}]}}"#, template_var);
+
+        serde_json::from_str(&config).unwrap()
+    }
+
+    #[test]
+    fn template_vars_must_declare_a_type() {
+        let typed = [
+            r#"{"is_template_var": true, "is_text": true}"#,
+            r#"{"is_template_var": true, "is_text": false}"#,
+            r#"{"is_template_var": true, "type": "date"}"#,
+            r#"{}"#,
+        ];
+        for param in &typed {
+            assert!(check_template_var_types(&config(param)).is_ok(), "{}", param);
+        }
+
+        let err = check_template_var_types(&config(r#"{"is_template_var": true}"#)).unwrap_err();
+        assert_eq!(err.to_string(), "Template var 'manager_id' of endpoint 'managers' must declare its `type` or `is_text`");
+    }
+}
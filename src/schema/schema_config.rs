@@ -3,8 +3,10 @@ use indexmap::IndexMap;
 
 use super::{
     ParamKey,
+    ParamType,
     FilterType,
 };
+use crate::format::CsvOptions;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct SchemaConfig {
@@ -18,6 +20,7 @@ pub struct EndpointConfig {
     pub sql_select: SqlSelectConfig,
     pub primary: Option<String>,
     pub interface: InterfaceConfig,
+    pub csv: Option<CsvOptions>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -27,10 +30,14 @@ pub struct InterfaceConfig(pub IndexMap<ParamKey, ParamValueConfig>);
 pub struct ParamValueConfig {
     pub column: Option<String>,
     pub filter_type: Option<FilterType>,
+    #[serde(rename="type")]
+    pub param_type: Option<ParamType>,
     pub visible: Option<bool>,
     pub dimension: Option<DimensionConfig>,
     pub is_text: Option<bool>,
     pub is_template_var: Option<bool>,
+    pub max_members: Option<usize>,
+    pub allow_regex: Option<bool>,
 }
 
 // TODO remove. template sql should replace the need for this.
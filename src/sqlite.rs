@@ -0,0 +1,185 @@
+use failure::{Error, bail, format_err};
+use futures::{Sink, Stream};
+use futures::sync::mpsc;
+use futures_cpupool::{Builder, CpuPool};
+use regex::Regex;
+use rusqlite::{Connection, OpenFlags};
+use rusqlite::types::Value;
+use std::collections::HashMap;
+
+use crate::backend::{Backend, SqlQuery, CHUNK_SIZE};
+use crate::dataframe::DataFrame;
+use crate::query::CountQuery;
+use crate::query_ir::{QueryIr, FilterValue};
+
+mod df;
+mod sql;
+
+use self::df::statement_to_dfs;
+use self::sql::{sqlite_sql, sqlite_count_sql};
+
+/// Sqlite connections can't be shared between threads, so a read-only
+/// connection is opened for each query.
+///
+/// Queries block, so they run on a fixed pool of worker threads; queries
+/// beyond the pool size wait for a free worker.
+#[derive(Clone)]
+pub struct Sqlite {
+    path: String,
+    pool: CpuPool,
+}
+
+impl Sqlite {
+    /// Pool size can be set through the url, e.g.
+    /// sqlite://path/to/file.db?pool_max=8
+    /// The default is the number of cpus.
+    pub fn from_path(path: &str) -> Result<Self, Error> {
+        let mut parts = path.splitn(2, '?');
+        let path = parts.next().unwrap_or_default();
+
+        let mut pool = Builder::new();
+        pool.name_prefix("beryl-sqlite-");
+
+        if let Some(options) = parts.next() {
+            for option in options.split('&') {
+                match &option.splitn(2, '=').collect::<Vec<_>>()[..] {
+                    ["pool_max", n] => {
+                        let n: usize = n.parse()
+                            .map_err(|_| format_err!("sqlite pool_max must be a number"))?;
+                        if n == 0 {
+                            bail!("sqlite pool_max must be at least 1");
+                        }
+                        pool.pool_size(n);
+                    },
+                    _ => bail!("Unknown sqlite url option '{}'", option),
+                }
+            }
+        }
+
+        // open once so that a bad path fails at startup instead of on a request
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        Ok(Sqlite {
+            path: path.to_owned(),
+            pool: pool.create(),
+        })
+    }
+}
+
+impl Backend for Sqlite {
+    fn exec_sql(&self, sql: SqlQuery) -> Box<Stream<Item=DataFrame, Error=Error>> {
+        let path = self.path.clone();
+
+        let SqlQuery { sql, params } = sql;
+        let params: Vec<Value> = params.into_iter()
+            .map(|param| {
+                match param {
+                    FilterValue::Text(s) => Value::Text(s),
+                    FilterValue::Int(n) => Value::Integer(n),
+                    // too large for a sqlite integer; sqlite will apply
+                    // numeric affinity when comparing to a numeric column
+                    FilterValue::UInt(n) => Value::Text(n.to_string()),
+                    FilterValue::Float(n) => Value::Real(n),
+                    // sqlite has no date type; dates are stored as iso8601 text
+                    FilterValue::Date(_) |
+                    FilterValue::DateTime(_) => Value::Text(param.to_string()),
+                    FilterValue::Bool(b) => Value::Integer(b as i64),
+                    FilterValue::Decimal(s) => Value::Text(s),
+                }
+            })
+            .collect();
+
+        // sqlite is embedded and blocking, so the query runs on a pool
+        // worker. The channel is bounded, so the worker waits for each chunk
+        // to be taken before reading more rows; if the response is dropped
+        // (e.g. the client disconnects), sending fails and the worker is
+        // freed.
+        let (tx, rx) = mpsc::channel(1);
+
+        self.pool.spawn_fn(move || {
+            let mut tx = tx.wait();
+
+            let res = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .map_err(Error::from)
+                .and_then(|conn| {
+                    add_regexp_function(&conn)?;
+
+                    let mut statement = conn.prepare(&sql)?;
+
+                    statement_to_dfs(&mut statement, &params, CHUNK_SIZE, |df| {
+                        tx.send(Ok(df)).map_err(|_| format_err!("sqlite result receiver dropped"))
+                    })
+                });
+
+            if let Err(err) = res {
+                // the receiver may also be gone here; nothing left to do then
+                let _ = tx.send(Err(err));
+            }
+
+            Ok::<(), ()>(())
+        })
+        .forget();
+
+        let stream = rx
+            .then(|res| {
+                match res {
+                    Ok(chunk) => chunk,
+                    Err(()) => unreachable!("mpsc receiver never errors"),
+                }
+            });
+
+        Box::new(stream)
+    }
+
+    fn generate_sql(&self, query_ir: QueryIr) -> SqlQuery {
+        sqlite_sql(query_ir)
+    }
+
+    fn generate_count_sql(&self, query_ir: QueryIr, _count: &CountQuery) -> SqlQuery {
+        sqlite_count_sql(query_ir)
+    }
+
+    // https://users.rust-lang.org/t/solved-is-it-possible-to-clone-a-boxed-trait-object/1714/4
+    fn box_clone(&self) -> Box<dyn Backend + Send + Sync> {
+        Box::new((*self).clone())
+    }
+}
+
+/// Sqlite parses `x REGEXP y` as `regexp(y, x)`, but doesn't define the
+/// function itself. Compiled regexes are cached for the connection, since
+/// the pattern is the same for every row.
+fn add_regexp_function(conn: &Connection) -> Result<(), Error> {
+    let mut cached_regexes: HashMap<String, Regex> = HashMap::new();
+
+    conn.create_scalar_function("regexp", 2, true, move |ctx| {
+        let regex_s = ctx.get::<String>(0)?;
+
+        // like other sql operators, a null never matches
+        let text = match ctx.get::<Option<String>>(1)? {
+            Some(text) => text,
+            None => return Ok(false),
+        };
+
+        if !cached_regexes.contains_key(&regex_s) {
+            let regex = Regex::new(&regex_s)
+                .map_err(|err| rusqlite::Error::UserFunctionError(Box::new(err)))?;
+            cached_regexes.insert(regex_s.clone(), regex);
+        }
+
+        Ok(cached_regexes[&regex_s].is_match(&text))
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn url_options() {
+        assert!(Sqlite::from_path("test.db?pool_max=0").is_err());
+        assert!(Sqlite::from_path("test.db?pool_max=x").is_err());
+        assert!(Sqlite::from_path("test.db?pool_min=1").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! Convert sqlite rows to DataFrame
+//!
+//! Sqlite values are dynamically typed, so the `ColumnData` variant is
+//! chosen from the declared column type, following sqlite's rules for
+//! column affinity: https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+//!
+//! Declared types don't carry nullability, so every column is nullable.
+
+use failure::{Error, format_err};
+use rusqlite::{Row, Statement};
+use rusqlite::types::{Value, ValueRef};
+
+use crate::dataframe::{DataFrame, Column, ColumnData};
+
+/// Reads the rows into DataFrames of up to `chunk_size` rows, passing
+/// each one to `emit`.
+pub fn statement_to_dfs<F>(
+    statement: &mut Statement,
+    params: &[Value],
+    chunk_size: usize,
+    mut emit: F,
+    ) -> Result<(), Error>
+    where F: FnMut(DataFrame) -> Result<(), Error>
+{
+    let columns: Vec<_> = statement.columns()
+        .iter()
+        .map(|col| {
+            let decl_type = col.decl_type()
+                .map(|t| t.to_uppercase())
+                .unwrap_or_default();
+
+            (col.name().to_owned(), decl_type)
+        })
+        .collect();
+
+    let new_df = || -> Vec<Column> {
+        columns.iter()
+            .map(|(name, decl_type)| Column::new(name.clone(), empty_column_data(decl_type)))
+            .collect()
+    };
+
+    let mut df = new_df();
+    let mut row_count = 0;
+
+    let mut rows = statement.query(params)?;
+
+    while let Some(row) = rows.next()? {
+        for col_idx in 0..df.len() {
+            let column_data = df
+                .get_mut(col_idx)
+                .expect("logic checked?")
+                .column_data();
+
+            match column_data {
+                ColumnData::NullableInt64(col_data) => {
+                    col_data.push(row.get(col_idx)?);
+                },
+                ColumnData::NullableFloat64(col_data) => {
+                    col_data.push(row.get(col_idx)?);
+                },
+                ColumnData::NullableUInt8(col_data) => {
+                    let val: Option<bool> = row.get(col_idx)?;
+                    col_data.push(val.map(|b| b as u8));
+                },
+                ColumnData::NullableText(col_data) => {
+                    col_data.push(get_text(row, col_idx)?);
+                },
+                ColumnData::NullableDecimal(col_data) => {
+                    col_data.push(get_text(row, col_idx)?);
+                },
+                _ => unreachable!("only nullable columns are created from sqlite rows"),
+            }
+        }
+
+        row_count += 1;
+
+        if row_count == chunk_size {
+            emit(DataFrame::from_vec(std::mem::replace(&mut df, new_df())))?;
+            row_count = 0;
+        }
+    }
+
+    if row_count > 0 {
+        emit(DataFrame::from_vec(df))?;
+    }
+
+    Ok(())
+}
+
+fn empty_column_data(decl_type: &str) -> ColumnData {
+    if decl_type.contains("INT") {
+        ColumnData::NullableInt64(vec![])
+    } else if decl_type.contains("CHAR") || decl_type.contains("CLOB") || decl_type.contains("TEXT") {
+        ColumnData::NullableText(vec![])
+    } else if decl_type.contains("REAL") || decl_type.contains("FLOA") || decl_type.contains("DOUB") {
+        ColumnData::NullableFloat64(vec![])
+    } else if decl_type.contains("DEC") || decl_type.contains("NUMERIC") {
+        // read as text; a decimal stored as real is already lossy
+        ColumnData::NullableDecimal(vec![])
+    } else if decl_type.contains("BOOL") {
+        // there's no bool ColumnData; follow clickhouse and use UInt8
+        ColumnData::NullableUInt8(vec![])
+    } else {
+        // blob, numeric (e.g. date) or an expression with no
+        // declared type; text is the only lossless representation.
+        ColumnData::NullableText(vec![])
+    }
+}
+
+/// Reads any sqlite value as text, since a column with text affinity
+/// may still hold other storage classes.
+fn get_text(row: &Row, col_idx: usize) -> Result<Option<String>, Error> {
+    let val = match row.get_raw_checked(col_idx)? {
+        ValueRef::Null => None,
+        ValueRef::Integer(n) => Some(n.to_string()),
+        ValueRef::Real(n) => Some(n.to_string()),
+        ValueRef::Text(s) => Some(s.to_owned()),
+        ValueRef::Blob(b) => {
+            let s = std::str::from_utf8(b)
+                .map_err(|_| format_err!("Non utf8 blob in column {} is not supported by beryl", col_idx))?;
+            Some(s.to_owned())
+        },
+    };
+
+    Ok(val)
+}
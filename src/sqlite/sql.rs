@@ -0,0 +1,222 @@
+use itertools::join;
+
+use crate::backend::SqlQuery;
+use crate::query::NullsOrder;
+use crate::query_ir::{QueryIr, Constraint, CursorIr, FilterExpr, FilterIr, FilterValue, StringMatchKind};
+
+pub fn sqlite_sql(query_ir: QueryIr) -> SqlQuery {
+    let project_cols_sql = join(query_ir.projection, ", ");
+
+    let table = query_ir.table;
+
+    let mut params = vec![];
+
+    let filter_sql = filter_sql(&query_ir.filters, query_ir.cursor.as_ref(), &mut params);
+
+    let sort_sql = if !query_ir.sort.is_empty() {
+        // `nulls first` and `nulls last` need sqlite 3.30, so they're emulated
+        // by sorting on whether the column is null first
+        let sorts = query_ir.sort.iter()
+            .map(|srt| {
+                let column = srt.column.clone();
+                let sort = format!("{} {}", column, srt.direction.sql_string());
+
+                match srt.nulls {
+                    Some(NullsOrder::First) => format!("({}) is null desc, {}", column, sort),
+                    Some(NullsOrder::Last) => format!("({}) is null asc, {}", column, sort),
+                    None => sort,
+                }
+            });
+
+        format!("order by {}", join(sorts, ", "))
+    } else {
+        "".into()
+    };
+
+    let limit_sql = {
+        if let Some(lmt) = query_ir.limit {
+            if let Some(offset) = lmt.offset {
+                format!("limit {} offset {}", lmt.n, offset)
+            } else {
+                format!("limit {}", lmt.n)
+            }
+        } else {
+            "".to_string()
+        }
+    };
+
+    let sql = format!("select {} from {} {} {} {}",
+        project_cols_sql,
+        table,
+        filter_sql,
+        sort_sql,
+        limit_sql,
+    );
+
+    SqlQuery {
+        sql,
+        params,
+    }
+}
+
+/// Counts the rows matching the filters, ignoring sort and limit.
+///
+/// sqlite keeps no row count statistics, so the count is always exact.
+pub fn sqlite_count_sql(query_ir: QueryIr) -> SqlQuery {
+    let mut params = vec![];
+
+    let filter_sql = filter_sql(&query_ir.filters, None, &mut params);
+
+    let sql = format!("select count(*) from {} {}", query_ir.table, filter_sql);
+
+    SqlQuery {
+        sql,
+        params,
+    }
+}
+
+fn filter_sql(filter_exprs: &[FilterExpr], cursor: Option<&CursorIr>, params: &mut Vec<FilterValue>) -> String {
+    let mut filters = vec![];
+
+    for expr in filter_exprs {
+        filters.push(expr.sql_string(&mut |f| constraint_sql(f, params)));
+    }
+
+    // keyset pagination: the rows after the cursor, in the sort order
+    if let Some(cursor) = cursor {
+        let cursor_sql = cursor.sql_string(|col| col.to_owned(), |value| {
+            params.push(value.clone());
+            "?".to_owned()
+        });
+
+        filters.extend(cursor_sql);
+    }
+
+    if !filters.is_empty() {
+        format!("where {}", join(filters, " and "))
+    } else {
+        "".into()
+    }
+}
+
+fn constraint_sql(f: &FilterIr, params: &mut Vec<FilterValue>) -> String {
+    match &f.constraint {
+        Constraint::CompareList ( ref compare_list )=> {
+            let comparisons = compare_list.iter()
+                .map(|compare| {
+                    params.push(compare.n.clone());
+
+                    format!("{} {} ?",
+                        f.column,
+                        compare.comparison.sql_string(),
+                    )
+                });
+
+            join(comparisons, " and ")
+        },
+        Constraint::ExactMatch { ref pattern } => {
+            params.push(pattern.clone());
+
+            format!("{} = ?", f.column)
+        },
+        Constraint::StringMatch { ref kind, ref substring } => {
+            match kind {
+                StringMatchKind::Contains |
+                StringMatchKind::StartsWith |
+                StringMatchKind::EndsWith => {
+                    params.push(FilterValue::Text(kind.like_pattern(substring)));
+
+                    // sqlite LIKE is already case-insensitive (for ascii),
+                    // and has no default escape char
+                    format!("{} LIKE ? ESCAPE '\\'", f.column)
+                },
+                StringMatchKind::ContainsCaseSensitive => {
+                    params.push(FilterValue::Text(substring.clone()));
+
+                    format!("instr({}, ?) > 0", f.column)
+                },
+            }
+        },
+        Constraint::RegexMatch { ref regex } => {
+            params.push(FilterValue::Text(regex.clone()));
+
+            // uses the `regexp` function registered on the connection
+            format!("{} REGEXP ?", f.column)
+        },
+        Constraint::InArray { ref in_members, ref not_in_members } => {
+            // sqlite has no array type, so arrays are expected
+            // to be stored as json arrays.
+            let mut res = vec![];
+            for m in in_members {
+                params.push(m.clone());
+
+                res.push(format!("EXISTS (SELECT 1 FROM json_each({}) WHERE value = ?)",
+                    f.column,
+                ));
+            }
+            if !not_in_members.is_empty() {
+                params.extend(not_in_members.iter().cloned());
+
+                // json_each(NULL) is empty, so nulls are excluded explicitly,
+                // as in the other backends
+                res.push(format!("{} IS NOT NULL AND NOT EXISTS (SELECT 1 FROM json_each({}) WHERE value IN ({}))",
+                    f.column,
+                    f.column,
+                    join(std::iter::repeat("?").take(not_in_members.len()), ", "),
+                ));
+            };
+
+            join(res, " AND ")
+        },
+        Constraint::OneOf { ref in_members, ref not_in_members } => {
+            let list_sql = |n: usize| join(std::iter::repeat("?").take(n), ", ");
+
+            let mut res = vec![];
+            if !in_members.is_empty() {
+                params.extend(in_members.iter().cloned());
+
+                res.push(format!("{} IN ({})", f.column, list_sql(in_members.len())));
+            }
+            if !not_in_members.is_empty() {
+                params.extend(not_in_members.iter().cloned());
+
+                res.push(format!("{} NOT IN ({})", f.column, list_sql(not_in_members.len())));
+            }
+
+            join(res, " AND ")
+        },
+        Constraint::IsNull => format!("{} IS NULL", f.column),
+        Constraint::IsNotNull => format!("{} IS NOT NULL", f.column),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query_ir::test::{HOSTILE_VALUES, assert_only_bound, query_ir_with_every_constraint};
+
+    #[test]
+    fn hostile_values_are_bound() {
+        let benign = sqlite_sql(query_ir_with_every_constraint("a"));
+
+        for value in HOSTILE_VALUES {
+            assert_only_bound(value, sqlite_sql(query_ir_with_every_constraint(value)), &benign);
+        }
+    }
+
+    #[test]
+    fn hostile_values_are_bound_in_count() {
+        let benign = sqlite_count_sql(query_ir_with_every_constraint("a"));
+
+        for value in HOSTILE_VALUES {
+            assert_only_bound(value, sqlite_count_sql(query_ir_with_every_constraint(value)), &benign);
+        }
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        let SqlQuery { params, .. } = sqlite_sql(query_ir_with_every_constraint("100%_\\"));
+
+        assert!(params.contains(&FilterValue::Text("%100\\%\\_\\\\%".to_owned())));
+    }
+}
@@ -0,0 +1,17 @@
+create table test_beryl (
+    account          integer,
+    city_state       text,
+    number_employees integer,
+    products         text[],
+    store_label      text,
+    delivers         boolean,
+    opened           date
+);
+
+insert into test_beryl ( account, city_state, number_employees, products, store_label, delivers, opened) values
+    (1, 'Boston MA', 100, ARRAY['AMZN', 'APPL'], 'Store1', false, '2015-03-01'),
+    (2, 'Somerville MA', 200, ARRAY['AMZN', 'NIKE'], 'Store2', true, '2016-07-15'),
+    (3, 'Cambridge MA', 500, ARRAY['NIKE', 'FILO'], 'Store3', false, '2018-01-20'),
+    (4, 'Malden MA', 900, ARRAY['APPL', 'ZAZA'], 'Store4', true, '2019-05-05'),
+    (5, 'Quincy MA', 700, ARRAY['APPL', 'FILO'], 'Store5', false, '2019-11-30')
+;
@@ -0,0 +1,17 @@
+create table test_beryl (
+    account          integer not null,
+    city_state       text not null,
+    number_employees integer not null,
+    products         text not null, -- json array
+    store_label      text not null,
+    delivers         boolean not null,
+    opened           date not null -- iso8601 text
+);
+
+insert into test_beryl ( account, city_state, number_employees, products, store_label, delivers, opened) values
+    (1, 'Boston MA', 100, '["AMZN", "APPL"]', 'Store1', 0, '2015-03-01'),
+    (2, 'Somerville MA', 200, '["AMZN", "NIKE"]', 'Store2', 1, '2016-07-15'),
+    (3, 'Cambridge MA', 500, '["NIKE", "FILO"]', 'Store3', 0, '2018-01-20'),
+    (4, 'Malden MA', 900, '["APPL", "ZAZA"]', 'Store4', 1, '2019-05-05'),
+    (5, 'Quincy MA', 700, '["APPL", "FILO"]', 'Store5', 0, '2019-11-30')
+;
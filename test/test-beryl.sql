@@ -4,6 +4,7 @@ create table test_beryl (
     number_employees UInt32,
     products         Array(String),
     store_label      String,
-    delivers         UInt8 -- bool
+    delivers         UInt8, -- bool
+    opened           Date
 ) ENGINE = Log
 ;